@@ -0,0 +1,130 @@
+//! Reader of BGZF files, the blocked gzip format written by `bgzip`, with random access by
+//! virtual offset.
+//!
+//! A virtual offset is the file offset of a compressed block shifted left by 16 bits, combined
+//! with an offset into the decompressed block, as used by tabix and CSI indices.
+
+use flate2::read::DeflateDecoder;
+use std::io::{self, BufRead, Read};
+
+/// Reads decompressed BGZF data one block at a time.
+pub(crate) struct Reader<R: io::Read + io::Seek> {
+    inner: R,
+    /// Decompressed data of the current block.
+    block: Vec<u8>,
+    /// Position in `block`.
+    pos: usize,
+    /// File offset of the current block.
+    coffset: u64,
+    /// File offset of the next block, where `inner` is positioned.
+    next_coffset: u64,
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+impl<R: io::Read + io::Seek> Reader<R> {
+    pub(crate) fn new(inner: R) -> Self {
+        Self {
+            inner,
+            block: vec![],
+            pos: 0,
+            coffset: 0,
+            next_coffset: 0,
+        }
+    }
+
+    /// Virtual offset of the next byte to read. At the end of a block, that is the start of
+    /// the next block.
+    pub(crate) fn virtual_offset(&self) -> u64 {
+        if self.pos == self.block.len() && !self.block.is_empty() {
+            self.next_coffset << 16
+        } else {
+            (self.coffset << 16) | self.pos as u64
+        }
+    }
+
+    /// Moves to a virtual offset, decompressing its block unless it is the current one.
+    pub(crate) fn seek(&mut self, offset: u64) -> io::Result<()> {
+        let coffset = offset >> 16;
+        if coffset != self.coffset || self.block.is_empty() {
+            self.inner.seek(io::SeekFrom::Start(coffset))?;
+            self.next_coffset = coffset;
+            self.read_block()?;
+        }
+        self.pos = (offset & 0xffff) as usize;
+        if self.pos > self.block.len() {
+            return Err(invalid("virtual offset past the end of a BGZF block"));
+        }
+        Ok(())
+    }
+
+    /// Decompresses the block at `next_coffset`. Returns false at the end of the file.
+    fn read_block(&mut self) -> io::Result<bool> {
+        self.coffset = self.next_coffset;
+        self.block.clear();
+        self.pos = 0;
+        let mut header = [0; 12];
+        match self.inner.read_exact(&mut header) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+            result => result?,
+        }
+        if header[..4] != [0x1f, 0x8b, 8, 4] {
+            return Err(invalid("not a BGZF file, compress it with bgzip"));
+        }
+        let xlen = u16::from_le_bytes([header[10], header[11]]) as usize;
+        let mut extra = vec![0; xlen];
+        self.inner.read_exact(&mut extra)?;
+        // The BC subfield holds the total block size minus one:
+        let mut bsize = None;
+        let mut i = 0;
+        while i + 4 <= extra.len() {
+            let len = u16::from_le_bytes([extra[i + 2], extra[i + 3]]) as usize;
+            if extra[i..i + 2] == [b'B', b'C'] && len == 2 && i + 6 <= extra.len() {
+                bsize = Some(u16::from_le_bytes([extra[i + 4], extra[i + 5]]) as usize + 1);
+            }
+            i += 4 + len;
+        }
+        let bsize = bsize.ok_or_else(|| invalid("BGZF block without a size"))?;
+        let cdata_len = bsize
+            .checked_sub(12 + xlen + 8)
+            .ok_or_else(|| invalid("invalid BGZF block size"))?;
+        let mut cdata = vec![0; cdata_len + 8];
+        self.inner.read_exact(&mut cdata)?;
+        let isize = u32::from_le_bytes(cdata[cdata_len + 4..].try_into().unwrap()) as usize;
+        self.block.reserve(isize);
+        DeflateDecoder::new(&cdata[..cdata_len]).read_to_end(&mut self.block)?;
+        if self.block.len() != isize {
+            return Err(invalid("corrupt BGZF block"));
+        }
+        self.next_coffset = self.coffset + bsize as u64;
+        Ok(true)
+    }
+}
+
+impl<R: io::Read + io::Seek> io::Read for Reader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl<R: io::Read + io::Seek> io::BufRead for Reader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        // Skipping empty blocks, such as the end-of-file marker:
+        while self.pos == self.block.len() {
+            if !self.read_block()? {
+                break;
+            }
+        }
+        Ok(&self.block[self.pos..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos = (self.pos + amt).min(self.block.len());
+    }
+}
@@ -0,0 +1,655 @@
+//! Compact binary annotation files written by `bedanno index`.
+//!
+//! Features are stored already parsed and normalized, in deflate-compressed blocks of about
+//! 64 KiB, in the order of the annotation file. Each run of features of one contig is
+//! followed by its directory, which lists the interval of every feature, sorted by start, with
+//! the block and position it is stored at. The file ends with a table of the directories and
+//! the offset of that table, so that readers decode only the directories of the contigs
+//! present in the BED file, and decompress only the blocks holding features near the BED
+//! records. Integers are LEB128 varints.
+
+use crate::contig::ContigAliases;
+use crate::error::{FileKind, ParseError, ParseErrorKind};
+use crate::gff::{self, GffLine};
+use crate::index::IntervalTree;
+use crate::{tabix, Interval, Options, Skipped, Verbosity};
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use std::collections::HashMap;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+const MAGIC: &[u8; 8] = b"BEDANNO\x02";
+
+/// Uncompressed size from which a block of features is written.
+const BLOCK_SIZE: usize = 1 << 16;
+
+/// Extension of binary annotation files.
+pub const EXTENSION: &str = "bidx";
+
+fn write_varint(buf: &mut Vec<u8>, mut x: u64) {
+    while x >= 0x80 {
+        buf.push((x as u8) | 0x80);
+        x >>= 7;
+    }
+    buf.push(x as u8);
+}
+
+fn read_varint(reader: &mut impl Read) -> io::Result<u64> {
+    let mut x = 0;
+    for shift in (0..64).step_by(7) {
+        let mut byte = [0];
+        reader.read_exact(&mut byte)?;
+        x |= ((byte[0] & 0x7f) as u64) << shift;
+        if byte[0] < 0x80 {
+            return Ok(x);
+        }
+    }
+    Err(invalid_data("invalid varint"))
+}
+
+fn write_string(buf: &mut Vec<u8>, s: &str) {
+    write_varint(buf, s.len() as u64);
+    buf.extend_from_slice(s.as_bytes());
+}
+
+fn read_string(buf: &mut &[u8]) -> io::Result<String> {
+    let len = read_varint(buf)?;
+    if len > buf.len() as u64 {
+        return Err(truncated());
+    }
+    let (s, rest) = buf.split_at(len as usize);
+    *buf = rest;
+    String::from_utf8(s.to_vec()).map_err(|_| invalid_data("invalid UTF-8 in string"))
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn truncated() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "truncated index file")
+}
+
+/// Reads `len` bytes, growing the buffer only as data arrives so that a corrupt length
+/// fails at the end of the file rather than allocating it up front.
+fn read_bytes(reader: &mut impl Read, len: u64) -> io::Result<Vec<u8>> {
+    let mut buf = vec![];
+    reader.take(len).read_to_end(&mut buf)?;
+    if (buf.len() as u64) < len {
+        return Err(truncated());
+    }
+    Ok(buf)
+}
+
+/// Writes a compressed block: its uncompressed and compressed lengths, then its data.
+fn write_block(writer: &mut impl Write, data: &[u8]) -> io::Result<()> {
+    let mut encoder = DeflateEncoder::new(vec![], Compression::default());
+    encoder.write_all(data)?;
+    let compressed = encoder.finish()?;
+    let mut header = vec![];
+    write_varint(&mut header, data.len() as u64);
+    write_varint(&mut header, compressed.len() as u64);
+    writer.write_all(&header)?;
+    writer.write_all(&compressed)
+}
+
+/// Reads and decompresses the block at the current position.
+fn read_block(reader: &mut impl Read) -> io::Result<Vec<u8>> {
+    let len = read_varint(reader)?;
+    let compressed = read_varint(reader)?;
+    let compressed = read_bytes(reader, compressed)?;
+    let mut data = vec![];
+    DeflateDecoder::new(compressed.as_slice())
+        .take(len)
+        .read_to_end(&mut data)?;
+    if data.len() as u64 != len {
+        return Err(invalid_data("block shorter than its length"));
+    }
+    Ok(data)
+}
+
+/// Counts the bytes written, to record the offsets of blocks and directories.
+struct CountingWriter<W> {
+    inner: W,
+    offset: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.offset += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Consecutive features of one contig being written.
+struct Segment {
+    contig: String,
+    /// Offsets of the blocks written so far.
+    blocks: Vec<u64>,
+    /// Uncompressed features not written yet.
+    block: Vec<u8>,
+    /// Interval of each feature, with the index of its block in `blocks`, its position in the
+    /// block and whether it is a gene or transcript.
+    features: Vec<(Interval, usize, usize, bool)>,
+}
+
+impl Segment {
+    fn new(contig: String) -> Self {
+        Self {
+            contig,
+            blocks: vec![],
+            block: vec![],
+            features: vec![],
+        }
+    }
+
+    fn push(&mut self, writer: &mut CountingWriter<impl Write>, rec: &GffLine) -> io::Result<()> {
+        if self.block.len() >= BLOCK_SIZE {
+            self.flush(writer)?;
+        }
+        let pos = self.block.len();
+        write_string(&mut self.block, &rec.feature_type);
+        self.block.push(rec.strand as u8);
+        write_varint(&mut self.block, rec.attributes().len() as u64);
+        for (key, value) in rec.attributes() {
+            write_string(&mut self.block, key);
+            write_string(&mut self.block, value);
+        }
+        let is_gene = rec.is_gene() || rec.is_transcript();
+        self.features
+            .push((rec.interval.clone(), self.blocks.len(), pos, is_gene));
+        Ok(())
+    }
+
+    fn flush(&mut self, writer: &mut CountingWriter<impl Write>) -> io::Result<()> {
+        if !self.block.is_empty() {
+            self.blocks.push(writer.offset);
+            write_block(writer, &self.block)?;
+            self.block.clear();
+        }
+        Ok(())
+    }
+
+    /// Writes the remaining features and the directory, and returns the contig name, the
+    /// offset of the directory and the number of features.
+    fn finish(mut self, writer: &mut CountingWriter<impl Write>) -> io::Result<(String, u64, u64)> {
+        self.flush(writer)?;
+        self.features.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut directory = vec![];
+        write_varint(&mut directory, self.blocks.len() as u64);
+        let mut prev = 0;
+        for &offset in &self.blocks {
+            write_varint(&mut directory, offset - prev);
+            prev = offset;
+        }
+        write_varint(&mut directory, self.features.len() as u64);
+        let mut prev_start = 0;
+        for (interval, block, pos, is_gene) in &self.features {
+            write_varint(&mut directory, interval.start - prev_start);
+            write_varint(&mut directory, interval.end - interval.start);
+            write_varint(&mut directory, (*block as u64) << 1 | *is_gene as u64);
+            write_varint(&mut directory, *pos as u64);
+            prev_start = interval.start;
+        }
+        let offset = writer.offset;
+        write_block(writer, &directory)?;
+        Ok((self.contig, offset, self.features.len() as u64))
+    }
+}
+
+/// Reads a GTF/GFF file and writes its features in binary form. Attributes are normalized
+/// according to `options.dialect`, and malformed lines are skipped if `options.lenient` is set.
+/// Features are written as they are read, so that only the directory of the current contig is
+/// held in memory. Returns the number of features written.
+pub fn write_index(
+    reader: impl io::Read,
+    writer: impl io::Write,
+    options: &Options,
+) -> anyhow::Result<usize> {
+    let mut writer = CountingWriter {
+        inner: io::BufWriter::new(writer),
+        offset: 0,
+    };
+    writer.write_all(MAGIC)?;
+    let mut skipped = Skipped::default();
+    let mut segment: Option<Segment> = None;
+    let mut directories = vec![];
+    let mut count = 0;
+    for rec in gff::Reader::new(reader, options.format, options.dialect) {
+        let rec = match rec {
+            Ok(rec) => rec,
+            Err(e) => {
+                skipped.record(e, options.lenient)?;
+                continue;
+            }
+        };
+        if segment.as_ref().is_none_or(|x| x.contig != rec.contig) {
+            if let Some(segment) = segment.take() {
+                directories.push(segment.finish(&mut writer)?);
+            }
+            segment = Some(Segment::new(rec.contig.clone()));
+        }
+        segment
+            .as_mut()
+            .expect("segment is started")
+            .push(&mut writer, &rec)?;
+        count += 1;
+    }
+    if let Some(segment) = segment {
+        directories.push(segment.finish(&mut writer)?);
+    }
+    if options.verbosity >= Verbosity::Normal {
+        skipped.report(FileKind::Annotation);
+    }
+
+    let offset = writer.offset;
+    let mut table = vec![];
+    write_varint(&mut table, directories.len() as u64);
+    for (contig, offset, features) in directories {
+        write_string(&mut table, &contig);
+        write_varint(&mut table, offset);
+        write_varint(&mut table, features);
+    }
+    write_block(&mut writer, &table)?;
+    writer.write_all(&offset.to_le_bytes())?;
+    writer.flush()?;
+    Ok(count)
+}
+
+/// Location of a feature in a binary annotation file.
+#[derive(Clone)]
+struct Entry {
+    interval: Interval,
+    /// Offset of the block holding the feature.
+    block: u64,
+    /// Position of the feature in the uncompressed block.
+    pos: usize,
+    /// Whether the feature is a gene or transcript.
+    is_gene: bool,
+}
+
+/// Reads the features of a binary annotation file near given intervals, contig by contig.
+pub(crate) struct Reader<R: io::Read + io::Seek> {
+    reader: io::BufReader<R>,
+    /// Contigs left to read, with the offsets of their directories and the intervals to read.
+    contigs: std::vec::IntoIter<(String, Vec<u64>, Vec<Interval>)>,
+    /// Whether to read all genes and transcripts.
+    genes: bool,
+    contig: String,
+    /// Features left to read on the current contig, sorted by block.
+    entries: std::vec::IntoIter<Entry>,
+    /// Offset and data of the last decompressed block.
+    block: Option<(u64, Vec<u8>)>,
+    /// Number of features read, reported in errors.
+    record: usize,
+}
+
+impl<R: io::Read + io::Seek> Reader<R> {
+    /// Opens a binary annotation file to read the features overlapping `intervals`, keyed by
+    /// normalized contig names, and the features within the extent of those, so that whole
+    /// genes and transcripts are read. With `genes`, every gene and transcript of these
+    /// contigs is read too, e.g. to find the nearest gene of each record.
+    pub(crate) fn new(
+        reader: R,
+        intervals: HashMap<String, Vec<Interval>>,
+        genes: bool,
+        aliases: &ContigAliases,
+    ) -> anyhow::Result<Self> {
+        let mut reader = io::BufReader::new(reader);
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(anyhow::anyhow!(
+                "Not a bedanno index file, or written by another version of bedanno"
+            ));
+        }
+        let len = reader.seek(SeekFrom::End(0))?;
+        reader.seek(SeekFrom::End(-8))?;
+        let mut offset = [0; 8];
+        reader.read_exact(&mut offset)?;
+        let offset = u64::from_le_bytes(offset);
+        if !(8..len - 8).contains(&offset) {
+            return Err(invalid_data("invalid directory table offset").into());
+        }
+        reader.seek(SeekFrom::Start(offset))?;
+        let table = read_block(&mut (&mut reader).take(len - 8 - offset))?;
+
+        // Directories of the wanted contigs, in the order of the file:
+        let mut table = table.as_slice();
+        let mut contigs: Vec<(String, Vec<u64>)> = vec![];
+        for _ in 0..read_varint(&mut table)? {
+            let contig = read_string(&mut table)?;
+            let directory = read_varint(&mut table)?;
+            read_varint(&mut table)?;
+            if directory >= offset {
+                return Err(invalid_data("invalid directory offset").into());
+            }
+            let normalized = aliases.normalize(&contig);
+            if !intervals.contains_key(normalized.as_ref()) {
+                continue;
+            }
+            match contigs.iter_mut().find(|(x, _)| *x == normalized) {
+                Some((_, directories)) => directories.push(directory),
+                None => contigs.push((normalized.into_owned(), vec![directory])),
+            }
+        }
+        let mut intervals = intervals;
+        let contigs = contigs
+            .into_iter()
+            .map(|(contig, directories)| {
+                let intervals = intervals.remove(&contig).unwrap_or_default();
+                (contig, directories, intervals)
+            })
+            .collect::<Vec<_>>();
+        Ok(Self {
+            reader,
+            contigs: contigs.into_iter(),
+            genes,
+            contig: String::new(),
+            entries: vec![].into_iter(),
+            block: None,
+            record: 0,
+        })
+    }
+
+    /// Reads the directories of the next contig and selects the features to read. Returns
+    /// false when no contig is left.
+    fn next_contig(&mut self) -> io::Result<bool> {
+        let Some((contig, directories, intervals)) = self.contigs.next() else {
+            return Ok(false);
+        };
+        let mut entries = vec![];
+        for offset in directories {
+            self.reader.seek(SeekFrom::Start(offset))?;
+            let directory = read_block(&mut self.reader)?;
+            read_directory(&directory, &mut entries)?;
+        }
+        let genes: Vec<Entry> = match self.genes {
+            true => entries.iter().filter(|x| x.is_gene).cloned().collect(),
+            false => vec![],
+        };
+        let tree = IntervalTree::new(
+            entries
+                .into_iter()
+                .map(|x| (x.interval.clone(), x))
+                .collect(),
+        );
+        let overlapping = |intervals: Vec<Interval>| {
+            tabix::merge(intervals)
+                .iter()
+                .flat_map(|x| tree.overlapping(x))
+                .map(|(x, _)| x.clone())
+                .collect::<Vec<Entry>>()
+        };
+        let extents = overlapping(intervals)
+            .into_iter()
+            .map(|x| x.interval)
+            .collect();
+        let mut entries = overlapping(extents);
+        entries.extend(genes);
+        // Reading each block once:
+        entries.sort_by_key(|x| (x.block, x.pos));
+        entries.dedup_by_key(|x| (x.block, x.pos));
+        self.contig = contig;
+        self.entries = entries.into_iter();
+        Ok(true)
+    }
+
+    fn read_feature(&mut self, entry: Entry) -> io::Result<GffLine> {
+        if self.block.as_ref().is_none_or(|(x, _)| *x != entry.block) {
+            self.reader.seek(SeekFrom::Start(entry.block))?;
+            self.block = Some((entry.block, read_block(&mut self.reader)?));
+        }
+        let (_, block) = self.block.as_ref().expect("block is read");
+        let mut buf = block
+            .get(entry.pos..)
+            .ok_or_else(|| invalid_data("feature position out of range"))?;
+        let feature_type = read_string(&mut buf)?;
+        let mut strand = [0];
+        buf.read_exact(&mut strand)?;
+        let count = read_varint(&mut buf)?;
+        let mut attributes = vec![];
+        for _ in 0..count {
+            attributes.push((read_string(&mut buf)?, read_string(&mut buf)?));
+        }
+        Ok(GffLine::new(
+            self.contig.clone(),
+            entry.interval,
+            feature_type,
+            strand[0] as char,
+            attributes,
+        ))
+    }
+}
+
+/// Decodes the blocks and features listed in a directory.
+fn read_directory(mut directory: &[u8], entries: &mut Vec<Entry>) -> io::Result<()> {
+    let buf = &mut directory;
+    let mut blocks = vec![];
+    let mut offset: u64 = 0;
+    for _ in 0..read_varint(buf)? {
+        offset = offset
+            .checked_add(read_varint(buf)?)
+            .ok_or_else(|| invalid_data("block offset out of range"))?;
+        blocks.push(offset);
+    }
+    let mut start: u64 = 0;
+    for _ in 0..read_varint(buf)? {
+        start = start
+            .checked_add(read_varint(buf)?)
+            .ok_or_else(|| invalid_data("feature start out of range"))?;
+        let end = start
+            .checked_add(read_varint(buf)?)
+            .ok_or_else(|| invalid_data("feature end out of range"))?;
+        let block = read_varint(buf)?;
+        entries.push(Entry {
+            interval: Interval::new(start, end),
+            block: *blocks
+                .get((block >> 1) as usize)
+                .ok_or_else(|| invalid_data("invalid block index"))?,
+            pos: read_varint(buf)? as usize,
+            is_gene: block & 1 == 1,
+        });
+    }
+    Ok(())
+}
+
+impl<R: io::Read + io::Seek> Iterator for Reader<R> {
+    type Item = Result<GffLine, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let result = match self.entries.next() {
+            Some(entry) => self.read_feature(entry),
+            None => match self.next_contig() {
+                Ok(true) => return self.next(),
+                Ok(false) => return None,
+                Err(e) => Err(e),
+            },
+        };
+        self.record += 1;
+        Some(result.map_err(|e| {
+            // No recovery from a corrupt file:
+            self.contigs = vec![].into_iter();
+            self.entries = vec![].into_iter();
+            ParseError::new(FileKind::Index, self.record, None, ParseErrorKind::Io(e))
+        }))
+    }
+}
+
+impl<R: io::Read + io::Seek> crate::Features for Reader<R> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn read(
+        index: &[u8],
+        intervals: &[(&str, Vec<Interval>)],
+        genes: bool,
+    ) -> anyhow::Result<Vec<GffLine>> {
+        let intervals = intervals
+            .iter()
+            .map(|(contig, x)| (contig.to_string(), x.clone()))
+            .collect();
+        let reader = Reader::new(
+            Cursor::new(index),
+            intervals,
+            genes,
+            &ContigAliases::default(),
+        )?;
+        Ok(reader.collect::<Result<Vec<GffLine>, ParseError>>()?)
+    }
+
+    /// Intervals covering whole contigs.
+    fn all() -> Vec<Interval> {
+        vec![Interval::new(0, u64::MAX)]
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let gtf = [
+            "chr2\tHAVANA\tgene\t101\t200\t.\t-\t.\tgene_name \"G2\"; tag \"a\"; tag \"b\";",
+            "chr1\tHAVANA\texon\t51\t60\t.\t+\t.\tgene_name \"G1\";",
+            "chr1\tHAVANA\tgene\t11\t100\t.\t+\t.\tgene_name \"G1\";",
+            "chr2\tHAVANA\texon\t151\t160\t.\t-\t.\tgene_name \"G2\";",
+        ]
+        .join("\n");
+        let mut index = vec![];
+        let count = write_index(gtf.as_bytes(), &mut index, &Options::default()).unwrap();
+        assert_eq!(count, 4);
+
+        let expected = gff::Reader::new(gtf.as_bytes(), gff::Format::Gtf, None)
+            .collect::<Result<Vec<GffLine>, ParseError>>()
+            .unwrap();
+        let recs = read(&index, &[("1", all()), ("2", all())], false).unwrap();
+        // Contigs come in the order of the file, with normalized names:
+        let expected = expected
+            .into_iter()
+            .map(|mut x| {
+                x.contig = x.contig.replace("chr", "");
+                x
+            })
+            .collect::<Vec<GffLine>>();
+        assert!(recs[..2] == [expected[0].clone(), expected[3].clone()]);
+        assert!(recs[2..] == [expected[1].clone(), expected[2].clone()]);
+
+        assert_eq!(read(&index, &[("1", all())], false).unwrap().len(), 2);
+        assert!(read(&index, &[("3", all())], false).unwrap().is_empty());
+        assert!(read(gtf.as_bytes(), &[], false).is_err());
+    }
+
+    #[test]
+    fn test_selection() {
+        // Many small genes, so that features span several blocks:
+        let mut gtf = String::new();
+        for i in 0..5000 {
+            let start = 1 + i * 1000;
+            gtf.push_str(&format!(
+                "chr1\tHAVANA\tgene\t{start}\t{}\t.\t+\t.\tgene_name \"G{i}\"; gene_id \"ENSG{i:011}\";\n",
+                start + 499
+            ));
+        }
+        gtf.push_str("chr1\tHAVANA\ttranscript\t6000001\t6100000\t.\t+\t.\tgene_name \"LONG\";\n");
+        gtf.push_str("chr1\tHAVANA\texon\t6099901\t6100000\t.\t+\t.\tgene_name \"LONG\";\n");
+        gtf.push_str("chr1\tHAVANA\tgene\t6100001\t6100100\t.\t+\t.\tgene_name \"NEXT\";\n");
+        gtf.push_str("chr2\tHAVANA\tgene\t1\t100\t.\t+\t.\tgene_name \"OTHER\";\n");
+        let mut index = vec![];
+        write_index(gtf.as_bytes(), &mut index, &Options::default()).unwrap();
+        let names = |recs: Vec<GffLine>| {
+            recs.iter()
+                .map(|x| x.attribute("gene_name").unwrap_or_default().to_string())
+                .collect::<Vec<String>>()
+        };
+
+        let recs = read(&index, &[("1", vec![Interval::new(3100, 3200)])], false).unwrap();
+        assert_eq!(names(recs), ["G3"]);
+        let recs = read(&index, &[("1", vec![Interval::new(3600, 3700)])], false).unwrap();
+        assert!(recs.is_empty());
+        // Features within the extent of overlapping ones, e.g. exons of a transcript, are
+        // read along with it:
+        let recs = read(
+            &index,
+            &[("1", vec![Interval::new(6000000, 6000010)])],
+            false,
+        )
+        .unwrap();
+        assert_eq!(names(recs), ["LONG", "LONG"]);
+        let recs = read(
+            &index,
+            &[
+                (
+                    "1",
+                    vec![Interval::new(4999000, 4999010), Interval::new(0, 10)],
+                ),
+                ("2", vec![]),
+            ],
+            false,
+        )
+        .unwrap();
+        assert_eq!(names(recs), ["G0", "G4999"]);
+        assert_eq!(read(&index, &[("1", all())], false).unwrap().len(), 5003);
+
+        // All genes and transcripts, but no other features away from the intervals:
+        let recs = read(&index, &[("1", vec![Interval::new(0, 10)])], true).unwrap();
+        let genes = names(recs);
+        assert_eq!(genes.len(), 5002);
+        assert_eq!(genes[5000..], ["LONG", "NEXT"]);
+        let recs = read(&index, &[("2", vec![])], true).unwrap();
+        assert_eq!(names(recs), ["OTHER"]);
+    }
+
+    #[test]
+    fn test_corrupt_index() {
+        let gtf = "chr1\tHAVANA\tgene\t11\t100\t.\t+\t.\tgene_name \"G1\";";
+        let mut index = vec![];
+        write_index(gtf.as_bytes(), &mut index, &Options::default()).unwrap();
+        assert_eq!(read(&index, &[("1", all())], false).unwrap().len(), 1);
+
+        // Every truncation fails with an error:
+        for len in 0..index.len() {
+            assert!(read(&index[..len], &[("1", all())], false).is_err());
+        }
+        // Changed bytes may go unnoticed in compressed data, but never cause a panic:
+        for i in 0..index.len() {
+            let mut corrupt = index.clone();
+            corrupt[i] ^= 0xff;
+            let _ = read(&corrupt, &[("1", all())], false);
+        }
+
+        // Huge lengths and table offsets:
+        let mut garbage = MAGIC.to_vec();
+        write_varint(&mut garbage, u64::MAX);
+        write_varint(&mut garbage, u64::MAX);
+        garbage.extend_from_slice(&8u64.to_le_bytes());
+        assert!(read(&garbage, &[("1", all())], false).is_err());
+        let mut garbage = MAGIC.to_vec();
+        garbage.extend_from_slice(&u64::MAX.to_le_bytes());
+        assert!(read(&garbage, &[("1", all())], false).is_err());
+
+        // Coordinates overflowing u64:
+        let mut garbage = MAGIC.to_vec();
+        let mut directory = vec![];
+        write_varint(&mut directory, 0);
+        write_varint(&mut directory, 1);
+        write_varint(&mut directory, u64::MAX);
+        write_varint(&mut directory, u64::MAX);
+        write_block(&mut garbage, &directory).unwrap();
+        let offset = garbage.len() as u64;
+        let mut table = vec![];
+        write_varint(&mut table, 1);
+        write_string(&mut table, "chr1");
+        write_varint(&mut table, 8);
+        write_varint(&mut table, 1);
+        write_block(&mut garbage, &table).unwrap();
+        garbage.extend_from_slice(&offset.to_le_bytes());
+        let e = read(&garbage, &[("1", all())], false).err().unwrap();
+        assert!(e.to_string().contains("out of range"));
+    }
+}
@@ -0,0 +1,111 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io::{self, BufRead};
+
+/// Reconciles contig names between BED and annotation files, e.g. `chr1`, `1` and `NC_000001.11`.
+///
+/// Names are compared without the `chr` prefix and with `M` written as `MT`, so UCSC and
+/// Ensembl names match without any configuration. Other names, such as RefSeq accessions,
+/// can be matched with an alias table.
+#[derive(Clone, Debug, Default)]
+pub struct ContigAliases {
+    /// Maps every alias to the normalized name of its sequence.
+    aliases: HashMap<String, String>,
+}
+
+impl ContigAliases {
+    /// Reads an alias table in UCSC chromAlias format. Files starting with a `# ucsc` header
+    /// have one line per sequence, with all of its names separated by tabs. Files without it
+    /// may also use the legacy 3-column format, `alias<TAB>name<TAB>source`, which is assumed
+    /// when their first line has 3 columns. Other lines starting with `#` are ignored, and
+    /// lines with a single name are skipped with a warning.
+    pub fn from_reader(reader: impl io::Read) -> anyhow::Result<Self> {
+        let mut aliases = HashMap::new();
+        let mut legacy = None;
+        for (i, line) in io::BufReader::new(reader).lines().enumerate() {
+            let line = line?;
+            if line.starts_with("# ucsc") && legacy.is_none() {
+                legacy = Some(false);
+            }
+            if line.starts_with('#') || line.trim().is_empty() {
+                continue;
+            }
+            let names = line
+                .split('\t')
+                .map(|x| x.trim())
+                .filter(|x| !x.is_empty())
+                .collect::<Vec<&str>>();
+            if names.len() < 2 {
+                eprintln!(
+                    "Skipped contig alias line {}: expected at least 2 names",
+                    i + 1
+                );
+                continue;
+            }
+            // The source column of the legacy format is not a name:
+            let (canonical, names) = match *legacy.get_or_insert(names.len() == 3) {
+                true => (names[1], &names[..2]),
+                false => (names[0], &names[..]),
+            };
+            let canonical = strip_prefix(canonical);
+            for name in names {
+                aliases.insert(name.to_string(), canonical.to_string());
+            }
+        }
+        Ok(Self { aliases })
+    }
+
+    /// Returns the name used to compare contigs across files.
+    pub fn normalize<'a>(&self, contig: &'a str) -> Cow<'a, str> {
+        match self.aliases.get(contig) {
+            Some(canonical) => Cow::Owned(canonical.clone()),
+            None => Cow::Borrowed(strip_prefix(contig)),
+        }
+    }
+}
+
+fn strip_prefix(contig: &str) -> &str {
+    let stripped = contig
+        .strip_prefix("chr")
+        .or_else(|| contig.strip_prefix("Chr"))
+        .or_else(|| contig.strip_prefix("CHR"))
+        .unwrap_or(contig);
+    if stripped == "M" {
+        "MT"
+    } else {
+        stripped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        let aliases = ContigAliases::from_reader(
+            "# ucsc\tassembly\tgenbank\trefseq\nchr1\t1\tCM000663.2\tNC_000001.11\nchrM\tMT\tJ01415.2\tNC_012920.1\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(aliases.normalize("chr1"), "1");
+        assert_eq!(aliases.normalize("NC_000001.11"), "1");
+        assert_eq!(aliases.normalize("NC_012920.1"), "MT");
+        assert_eq!(aliases.normalize("chrM"), "MT");
+        assert_eq!(aliases.normalize("chrX"), "X");
+        assert_eq!(aliases.normalize("GL000194.1"), "GL000194.1");
+    }
+
+    #[test]
+    fn test_legacy_format() {
+        let aliases = ContigAliases::from_reader(
+            "1\tchr1\tensembl\nNC_000001.11\tchr1\trefseq\nchrUn\nMT\tchrM\tensembl\n".as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(aliases.normalize("NC_000001.11"), "1");
+        assert_eq!(aliases.normalize("chr1"), "1");
+        assert_eq!(aliases.normalize("chrM"), "MT");
+        assert_eq!(aliases.normalize("refseq"), "refseq");
+        assert_eq!(aliases.normalize("chrUn"), "Un");
+    }
+}
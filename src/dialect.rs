@@ -0,0 +1,148 @@
+/// Attribute naming conventions of an annotation source.
+///
+/// Ranking and output fields use GENCODE attribute names (`gene_name`, `gene_type`,
+/// `transcript_type`, `transcript_support_level`, `tag "MANE_Select"`, ...). Features from
+/// other sources get these attributes added from their own equivalents when the file is read.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Dialect {
+    /// GENCODE GTF/GFF3, which already uses the canonical attribute names.
+    Gencode,
+    /// Ensembl GTF/GFF3: `gene_biotype`, `transcript_biotype`, `biotype`, and gene symbols
+    /// in the `Name` of GFF3 gene features.
+    Ensembl,
+    /// NCBI RefSeq GTF/GFF3: gene symbols in `gene`, `gene_biotype`, and `tag=MANE Select`.
+    RefSeq,
+}
+
+impl Dialect {
+    /// Guesses the dialect of a feature from its attributes.
+    pub(crate) fn detect(attributes: &[(String, String)]) -> Self {
+        let has = |key| attribute(attributes, key).is_some();
+        if has("gbkey") || has("Dbxref") || has("db_xref") {
+            Dialect::RefSeq
+        } else if has("gene_type") || has("transcript_type") {
+            Dialect::Gencode
+        } else if has("biotype") || has("gene_biotype") || has("transcript_biotype") {
+            Dialect::Ensembl
+        } else {
+            Dialect::Gencode
+        }
+    }
+
+    /// Adds canonical attributes derived from the source-specific ones to the attributes of a
+    /// gene or other feature. Attributes already present on the feature are left untouched.
+    pub(crate) fn normalize(&self, is_gene: bool, rec: &mut Vec<(String, String)>) {
+        match self {
+            Dialect::Gencode => {}
+            Dialect::Ensembl => {
+                if is_gene {
+                    copy_attribute(rec, "Name", "gene_name");
+                    copy_attribute(rec, "biotype", "gene_type");
+                } else {
+                    copy_attribute(rec, "biotype", "transcript_type");
+                }
+                copy_attribute(rec, "gene_biotype", "gene_type");
+                copy_attribute(rec, "transcript_biotype", "transcript_type");
+            }
+            Dialect::RefSeq => {
+                copy_attribute(rec, "gene", "gene_name");
+                copy_attribute(rec, "gene_biotype", "gene_type");
+                copy_attribute(rec, "transcript_biotype", "transcript_type");
+                if !is_gene && attribute(rec, "transcript_type").is_none() {
+                    let gbkey = attribute(rec, "gbkey").unwrap_or_default();
+                    if gbkey == "mRNA" || gbkey == "CDS" {
+                        set_attribute(rec, "transcript_type", "protein_coding");
+                    }
+                }
+                // Tags are written with spaces, e.g. `MANE Select`, `RefSeq Select`:
+                for (key, value) in rec.iter_mut() {
+                    if key == "tag" {
+                        *value = value.replace(' ', "_");
+                    }
+                }
+            }
+        }
+        // Ensembl reports TSL with a note, e.g. `1 (assigned to previous version 5)`:
+        if let Some(tsl) = attribute(rec, "transcript_support_level") {
+            if let Some((tsl, _)) = tsl.split_once(' ') {
+                let tsl = tsl.to_string();
+                for (key, value) in rec.iter_mut() {
+                    if key == "transcript_support_level" {
+                        *value = tsl.clone();
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// GENCODE equivalent of a feature type (column 3), e.g. `transcript` for the `mRNA` and
+/// `lnc_RNA` of RefSeq and Ensembl GFF3 files, or `UTR` for `five_prime_UTR`.
+pub(crate) fn canonical_feature_type(feature_type: &str) -> &str {
+    match feature_type {
+        x if x.ends_with("gene") => "gene",
+        x if x.ends_with("transcript") || x.ends_with("RNA") => "transcript",
+        "five_prime_UTR" | "three_prime_UTR" | "five_prime_utr" | "three_prime_utr" => "UTR",
+        x => x,
+    }
+}
+
+/// First value of an attribute.
+fn attribute<'a>(attributes: &'a [(String, String)], key: &str) -> Option<&'a str> {
+    attributes
+        .iter()
+        .find(|(k, _)| k == key)
+        .map(|(_, v)| v.as_str())
+}
+
+/// Sets `to` to the value of `from`, unless `to` is already set.
+fn copy_attribute(rec: &mut Vec<(String, String)>, from: &str, to: &str) {
+    if let Some(value) = attribute(rec, from) {
+        let value = value.to_string();
+        set_attribute(rec, to, &value);
+    }
+}
+
+/// Sets an attribute, unless it is already set.
+fn set_attribute(rec: &mut Vec<(String, String)>, key: &str, value: &str) {
+    if attribute(rec, key).is_none() {
+        rec.push((key.to_string(), value.to_string()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::gff::{Format, GffLine};
+
+    fn normalized(line: &str, format: Format) -> GffLine {
+        GffLine::from_line(line, 0, format, None).unwrap()
+    }
+
+    #[test]
+    fn test_refseq() {
+        let rec = normalized(
+            "NC_000013.11\tBestRefSeq\tmRNA\t32315508\t32400268\t.\t+\t.\tID=rna-NM_000059.4;Parent=gene-BRCA2;Dbxref=GeneID:675;gbkey=mRNA;gene=BRCA2;tag=MANE Select;transcript_id=NM_000059.4",
+            Format::Gff3,
+        );
+        assert_eq!(rec.attribute("gene_name"), Some("BRCA2"));
+        assert_eq!(rec.attribute("transcript_type"), Some("protein_coding"));
+        assert_eq!(rec.attribute("tag"), Some("MANE_Select"));
+    }
+
+    #[test]
+    fn test_ensembl() {
+        let rec = normalized(
+            "13\tensembl_havana\tgene\t32315508\t32400268\t.\t+\t.\tID=gene:ENSG00000139618;Name=BRCA2;biotype=protein_coding;gene_id=ENSG00000139618",
+            Format::Gff3,
+        );
+        assert_eq!(rec.attribute("gene_name"), Some("BRCA2"));
+        assert_eq!(rec.attribute("gene_type"), Some("protein_coding"));
+
+        let rec = normalized(
+            "13\tensembl_havana\ttranscript\t32315508\t32400268\t.\t+\t.\tgene_id \"ENSG00000139618\"; gene_name \"BRCA2\"; transcript_biotype \"protein_coding\"; transcript_support_level \"5 (assigned to previous version 3)\";",
+            Format::Gtf,
+        );
+        assert_eq!(rec.attribute("transcript_type"), Some("protein_coding"));
+        assert_eq!(rec.attribute("transcript_support_level"), Some("5"));
+    }
+}
@@ -0,0 +1,102 @@
+use std::fmt;
+use std::io;
+
+/// Input file a parsing error refers to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileKind {
+    Bed,
+    Annotation,
+    /// Binary annotation file written by `bedanno index`.
+    Index,
+}
+
+impl fmt::Display for FileKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FileKind::Bed => write!(f, "BED"),
+            FileKind::Annotation => write!(f, "GTF/GFF"),
+            FileKind::Index => write!(f, "index"),
+        }
+    }
+}
+
+/// What is wrong with an input line.
+#[derive(Debug)]
+pub enum ParseErrorKind {
+    /// The line could not be read.
+    Io(io::Error),
+    /// The line has fewer columns than the format requires.
+    MissingColumn,
+    /// A coordinate column is not a non-negative integer.
+    InvalidNumber(String),
+    /// The interval is empty or reversed, or a 1-based start is zero.
+    InvalidInterval { start: u64, end: u64 },
+    /// An attribute in the 9th GTF/GFF column has no value.
+    InvalidAttribute(String),
+}
+
+/// An error in a BED or GTF/GFF input line.
+#[derive(Debug)]
+pub struct ParseError {
+    pub file: FileKind,
+    /// 1-based line number, or record number in binary annotation files.
+    pub line: usize,
+    /// 1-based column number, if the error refers to a specific column.
+    pub column: Option<usize>,
+    pub kind: ParseErrorKind,
+}
+
+impl ParseError {
+    pub(crate) fn new(
+        file: FileKind,
+        line: usize,
+        column: Option<usize>,
+        kind: ParseErrorKind,
+    ) -> Self {
+        Self {
+            file,
+            line,
+            column,
+            kind,
+        }
+    }
+
+    /// Whether the error is confined to a single malformed line, which can be skipped in lenient mode.
+    pub fn is_recoverable(&self) -> bool {
+        !matches!(self.kind, ParseErrorKind::Io(_))
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let unit = match self.file {
+            FileKind::Index => "record",
+            _ => "line",
+        };
+        write!(f, "{} {unit} {}", self.file, self.line)?;
+        if let Some(column) = self.column {
+            write!(f, ", column {column}")?;
+        }
+        write!(f, ": ")?;
+        match &self.kind {
+            ParseErrorKind::Io(e) => write!(f, "cannot read line: {e}"),
+            ParseErrorKind::MissingColumn => write!(f, "missing column"),
+            ParseErrorKind::InvalidNumber(value) => {
+                write!(f, "cannot parse {value:?} as a coordinate")
+            }
+            ParseErrorKind::InvalidInterval { start, end } => {
+                write!(f, "invalid interval {start}-{end}")
+            }
+            ParseErrorKind::InvalidAttribute(attr) => write!(f, "attribute {attr:?} has no value"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match &self.kind {
+            ParseErrorKind::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,428 @@
+use crate::contig::ContigAliases;
+use crate::dialect::{canonical_feature_type, Dialect};
+use crate::error::{FileKind, ParseError, ParseErrorKind};
+use crate::{parse_coord, Interval};
+use std::collections::{HashMap, HashSet};
+use std::io::{self, BufRead};
+use std::path::Path;
+use std::sync::OnceLock;
+
+/// Syntax of the 9th (attributes) column of an annotation file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// GTF/GFF2: `key "value"; key "value";`. Every line carries its gene and transcript attributes.
+    Gtf,
+    /// GFF3: `key=value;key=value1,value2`, with URL-encoded values. Features are linked
+    /// into genes and transcripts with the `ID` and `Parent` attributes.
+    Gff3,
+}
+
+impl Format {
+    /// Guesses the format from the file extension, ignoring a trailing `.gz`.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let p = path.to_string_lossy();
+        let p = p.trim_end_matches(".gz");
+        if p.ends_with(".gff") || p.ends_with(".gff3") {
+            Some(Format::Gff3)
+        } else if p.ends_with(".gtf") || p.ends_with(".gff2") {
+            Some(Format::Gtf)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct GffLine {
+    pub(crate) contig: String,
+    pub(crate) interval: Interval,
+    pub(crate) feature_type: String,
+    pub(crate) strand: char,
+    /// Unparsed 9th column with the dialect to normalize it with, until attributes are parsed.
+    raw_attributes: Option<(String, Format, Option<Dialect>)>,
+    /// Attributes of the feature, followed by attributes inherited from its GFF3 ancestors.
+    /// Attributes with multiple values, such as the repeated `tag` of GTF files or the
+    /// comma-separated values of GFF3 files, have one entry per value. Parsed on first use,
+    /// as most features are never reported.
+    attributes: OnceLock<Vec<(String, String)>>,
+}
+
+impl PartialEq for GffLine {
+    fn eq(&self, other: &Self) -> bool {
+        self.contig == other.contig
+            && self.interval == other.interval
+            && self.feature_type == other.feature_type
+            && self.strand == other.strand
+            && self.attributes() == other.attributes()
+    }
+}
+
+impl GffLine {
+    /// Creates a feature with already parsed and normalized attributes.
+    pub(crate) fn new(
+        contig: String,
+        interval: Interval,
+        feature_type: String,
+        strand: char,
+        attributes: Vec<(String, String)>,
+    ) -> Self {
+        Self {
+            contig,
+            interval,
+            feature_type,
+            strand,
+            raw_attributes: None,
+            attributes: OnceLock::from(attributes),
+        }
+    }
+
+    /// Parses the `i`-th (0-based) line of a GTF/GFF file. The attributes column is checked
+    /// but only parsed and normalized according to `dialect` when first used.
+    pub(crate) fn from_line(
+        line: &str,
+        i: usize,
+        format: Format,
+        dialect: Option<Dialect>,
+    ) -> Result<Self, ParseError> {
+        let line = line.trim_end_matches('\r');
+        let tokens: Vec<&str> = line.split('\t').collect::<Vec<&str>>();
+        if tokens.len() < 9 {
+            return Err(ParseError::new(
+                FileKind::Annotation,
+                i + 1,
+                Some(tokens.len() + 1),
+                ParseErrorKind::MissingColumn,
+            ));
+        }
+        let start = parse_coord(&tokens, 3, FileKind::Annotation, i)?;
+        let end = parse_coord(&tokens, 4, FileKind::Annotation, i)?;
+        if start == 0 || start > end {
+            return Err(ParseError::new(
+                FileKind::Annotation,
+                i + 1,
+                None,
+                ParseErrorKind::InvalidInterval { start, end },
+            ));
+        }
+        if let Some(Err(attr)) = attribute_pairs(tokens[8], format).find(|x| x.is_err()) {
+            return Err(ParseError::new(
+                FileKind::Annotation,
+                i + 1,
+                Some(9),
+                ParseErrorKind::InvalidAttribute(attr.to_string()),
+            ));
+        }
+        Ok(GffLine {
+            contig: tokens[0].to_string(),
+            interval: Interval::new(start - 1, end),
+            feature_type: tokens[2].to_string(),
+            strand: tokens[6].chars().next().unwrap_or('.'),
+            raw_attributes: Some((tokens[8].to_string(), format, dialect)),
+            attributes: OnceLock::new(),
+        })
+    }
+
+    /// Whether the feature is a gene, e.g. `gene`, `ncRNA_gene` or `pseudogene`.
+    pub(crate) fn is_gene(&self) -> bool {
+        canonical_feature_type(&self.feature_type) == "gene"
+    }
+
+    /// Whether the feature is a transcript, e.g. `transcript`, `mRNA` or `lnc_RNA`.
+    pub(crate) fn is_transcript(&self) -> bool {
+        canonical_feature_type(&self.feature_type) == "transcript"
+    }
+
+    /// All attributes, parsed and normalized on first use.
+    pub(crate) fn attributes(&self) -> &[(String, String)] {
+        self.attributes.get_or_init(|| {
+            let Some((raw, format, dialect)) = &self.raw_attributes else {
+                return vec![];
+            };
+            let mut attributes = parse_attributes(raw, *format);
+            dialect
+                .unwrap_or_else(|| Dialect::detect(&attributes))
+                .normalize(self.is_gene(), &mut attributes);
+            attributes
+        })
+    }
+
+    pub(crate) fn attributes_mut(&mut self) -> &mut Vec<(String, String)> {
+        self.attributes();
+        self.raw_attributes = None;
+        self.attributes.get_mut().expect("attributes are parsed")
+    }
+
+    /// First value of an attribute.
+    pub(crate) fn attribute(&self, key: &str) -> Option<&str> {
+        self.attributes()
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub(crate) fn attribute_values<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a str> {
+        self.attributes()
+            .iter()
+            .filter(move |(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Splits the 9th GTF/GFF column into keys and raw values, or returns the offending
+/// attribute if one has no value. The GTF syntax also accepts `key=value` pairs.
+fn attribute_pairs(
+    annotation: &str,
+    format: Format,
+) -> impl Iterator<Item = Result<(&str, &str), &str>> {
+    annotation
+        .split(';')
+        .filter(|x| !x.trim().is_empty())
+        .map(move |attr| {
+            let separators: &[char] = match format {
+                Format::Gtf => &[' ', '='],
+                Format::Gff3 => &['='],
+            };
+            attr.trim().split_once(separators).ok_or(attr)
+        })
+}
+
+/// Parses the 9th GTF/GFF column into key-value pairs, skipping attributes without a value.
+///
+/// GTF values are unquoted. GFF3 values are URL-decoded, and multiple comma-separated values
+/// of one attribute are split into separate pairs.
+fn parse_attributes(annotation: &str, format: Format) -> Vec<(String, String)> {
+    let mut attributes = vec![];
+    for (key, value) in attribute_pairs(annotation, format).flatten() {
+        match format {
+            Format::Gtf => {
+                let value = value.trim().trim_matches('"').to_string();
+                attributes.push((key.to_string(), value));
+            }
+            Format::Gff3 => {
+                for value in value.split(',') {
+                    attributes.push((key.to_string(), percent_decode(value)));
+                }
+            }
+        }
+    }
+    attributes
+}
+
+/// Decodes `%XX` escapes used in GFF3 attribute values.
+fn percent_decode(s: &str) -> String {
+    if !s.contains('%') {
+        return s.to_string();
+    }
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok();
+            if let Some(b) = hex.and_then(|x| u8::from_str_radix(x, 16).ok()) {
+                decoded.push(b);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Resolves GFF3 `Parent` links so that exons, CDS and UTRs inherit gene names, biotypes
+/// and transcript tags from their transcripts and genes.
+///
+/// Parents are expected to precede their children within a contig, as they do in Ensembl,
+/// GENCODE and RefSeq files. Children of unknown parents are kept with their own attributes.
+#[derive(Default)]
+struct Hierarchy {
+    contig: String,
+    /// Attributes of every feature with an `ID` on the current contig, including inherited ones.
+    features: HashMap<String, Vec<(String, String)>>,
+}
+
+impl Hierarchy {
+    /// Attributes that identify a feature itself and are never inherited.
+    const OWN_ATTRIBUTES: [&'static str; 3] = ["ID", "Parent", "Name"];
+
+    fn resolve(&mut self, rec: &mut GffLine) {
+        if rec.contig != self.contig {
+            self.contig = rec.contig.clone();
+            self.features.clear();
+        }
+        let parent = rec.attribute("Parent").and_then(|x| self.features.get(x));
+        if let Some(parent) = parent {
+            let attributes = rec.attributes_mut();
+            let own = attributes.len();
+            for (key, value) in parent {
+                let is_own = attributes[..own].iter().any(|(k, _)| k == key);
+                if !Self::OWN_ATTRIBUTES.contains(&key.as_str()) && !is_own {
+                    attributes.push((key.clone(), value.clone()));
+                }
+            }
+        }
+        if let Some(id) = rec.attribute("ID") {
+            self.features
+                .insert(id.to_string(), rec.attributes().to_vec());
+        }
+    }
+}
+
+/// Reads features from a GTF/GFF file, skipping comments and directives.
+///
+/// Attributes are normalized to GENCODE names according to `dialect`, or to the dialect
+/// detected for each feature when it is `None`. Both happen lazily, when a feature's
+/// attributes are first used.
+pub(crate) struct Reader<R: io::Read> {
+    lines: std::iter::Enumerate<io::Lines<io::BufReader<R>>>,
+    format: Format,
+    dialect: Option<Dialect>,
+    hierarchy: Hierarchy,
+    /// Contigs to read, or `None` to read all of them.
+    contigs: Option<ContigFilter>,
+}
+
+/// Selects lines by contig name before they are parsed.
+struct ContigFilter {
+    /// Normalized names of the contigs to read.
+    wanted: HashSet<String>,
+    aliases: ContigAliases,
+    /// Contig of the previous line and whether it is wanted.
+    last: Option<(String, bool)>,
+}
+
+impl ContigFilter {
+    fn allows(&mut self, line: &str) -> bool {
+        let contig = line.split('\t').next().unwrap_or_default();
+        match &self.last {
+            Some((last, allowed)) if last == contig => *allowed,
+            _ => {
+                let allowed = self
+                    .wanted
+                    .contains(self.aliases.normalize(contig).as_ref());
+                self.last = Some((contig.to_string(), allowed));
+                allowed
+            }
+        }
+    }
+}
+
+impl<R: io::Read> Reader<R> {
+    pub(crate) fn new(reader: R, format: Format, dialect: Option<Dialect>) -> Self {
+        Self {
+            lines: io::BufReader::new(reader).lines().enumerate(),
+            format,
+            dialect,
+            hierarchy: Hierarchy::default(),
+            contigs: None,
+        }
+    }
+
+    /// Reads only the features of contigs whose normalized names are in `wanted`. Lines of
+    /// other contigs are skipped before they are parsed, so they are not validated either.
+    pub(crate) fn with_contigs(mut self, wanted: HashSet<String>, aliases: ContigAliases) -> Self {
+        self.contigs = Some(ContigFilter {
+            wanted,
+            aliases,
+            last: None,
+        });
+        self
+    }
+}
+
+impl<R: io::Read> Iterator for Reader<R> {
+    type Item = Result<GffLine, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for (i, line) in self.lines.by_ref() {
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => {
+                    let e = ParseErrorKind::Io(e);
+                    return Some(Err(ParseError::new(FileKind::Annotation, i + 1, None, e)));
+                }
+            };
+            if line.starts_with("##FASTA") {
+                // Sequences follow, no more features
+                return None;
+            }
+            if line.starts_with('#') || line.trim().is_empty() {
+                continue;
+            }
+            if !self.contigs.as_mut().is_none_or(|x| x.allows(&line)) {
+                continue;
+            }
+            let mut rec = match GffLine::from_line(&line, i, self.format, self.dialect) {
+                Ok(rec) => rec,
+                Err(e) => return Some(Err(e)),
+            };
+            if self.format == Format::Gff3 {
+                self.hierarchy.resolve(&mut rec);
+            }
+            return Some(Ok(rec));
+        }
+        None
+    }
+}
+
+impl<R: io::Read> crate::Features for Reader<R> {
+    fn held_features(&self) -> usize {
+        self.hierarchy.features.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gff3_hierarchy() {
+        let gff = [
+            "chr1\tensembl\tgene\t1\t1000\t.\t+\t.\tID=gene:G1;Name=BRCA2;biotype=protein_coding",
+            "chr1\tensembl\tmRNA\t1\t1000\t.\t+\t.\tID=transcript:T1;Parent=gene:G1;Name=BRCA2-201;tag=basic,MANE_Select",
+            "###",
+            "chr1\tensembl\texon\t1\t100\t.\t+\t.\tParent=transcript:T1;Name=E1;note=a%3Bb%2Cc",
+            "chr1\tensembl\tCDS\t51\t100\t.\t+\t0\tID=CDS:P1;Parent=transcript:T1",
+            "chr2\tensembl\tCDS\t51\t100\t.\t+\t0\tID=CDS:P2;Parent=transcript:T1",
+            "##FASTA",
+            ">chr1",
+        ]
+        .join("\n");
+        let recs = Reader::new(gff.as_bytes(), Format::Gff3, None)
+            .collect::<Result<Vec<GffLine>, ParseError>>()
+            .unwrap();
+        assert_eq!(recs.len(), 5);
+        assert_eq!(recs[2].attribute("gene_name"), Some("BRCA2"));
+        assert_eq!(recs[2].attribute("Name"), Some("E1"));
+        assert_eq!(recs[2].attribute("note"), Some("a;b,c"));
+        assert_eq!(recs[3].attribute("gene_name"), Some("BRCA2"));
+        assert_eq!(recs[3].attribute("biotype"), Some("protein_coding"));
+        assert_eq!(
+            recs[3].attribute_values("tag").collect::<Vec<&str>>(),
+            vec!["basic", "MANE_Select"]
+        );
+        assert_eq!(recs[4].attribute("gene_name"), None);
+    }
+
+    #[test]
+    fn test_wanted_contigs() {
+        let gtf = [
+            "chr1\thavana\tgene\t1\t100\t.\t+\t.\tgene_name \"A\";",
+            "chr2\thavana\tgene\t1\t100\t.\t+\t.\tnot an attribute;",
+            "chr2\thavana\tgene\t1",
+            "1\thavana\tgene\t201\t300\t.\t+\t.\tgene_name \"B\";",
+        ]
+        .join("\n");
+        let recs = Reader::new(gtf.as_bytes(), Format::Gtf, None)
+            .with_contigs(HashSet::from(["1".to_string()]), ContigAliases::default())
+            .collect::<Result<Vec<GffLine>, ParseError>>()
+            .unwrap();
+        assert_eq!(recs.len(), 2);
+        assert_eq!(recs[1].attribute("gene_name"), Some("B"));
+        assert!(Reader::new(gtf.as_bytes(), Format::Gtf, None)
+            .collect::<Result<Vec<GffLine>, ParseError>>()
+            .is_err());
+    }
+}
@@ -0,0 +1,499 @@
+use crate::contig::ContigAliases;
+use crate::gff::GffLine;
+use crate::region::{self, TranscriptModels};
+use crate::{Direction, Field, Interval, Options};
+use std::collections::HashMap;
+
+/// Extent of the overlap between a query region and an annotation feature.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Overlap {
+    /// Number of shared bases.
+    pub(crate) bases: u64,
+    /// Fraction of the query covered by the feature.
+    pub(crate) query_fraction: f64,
+    /// Fraction of the feature covered by the query.
+    pub(crate) feature_fraction: f64,
+}
+
+impl Overlap {
+    /// Measures the overlap of a query with a feature. An empty query is treated as the single
+    /// base at its start.
+    pub(crate) fn new(query: &Interval, feature: &Interval) -> Self {
+        let query = &Interval::new(query.start, query.end.max(query.start + 1));
+        let bases = query.overlap_len(feature);
+        let fraction = |x: &Interval| bases as f64 / (x.end - x.start).max(1) as f64;
+        Self {
+            bases,
+            query_fraction: fraction(query),
+            feature_fraction: fraction(feature),
+        }
+    }
+}
+
+/// Static interval tree over intervals sorted by start, laid out as an implicit balanced
+/// binary tree and augmented with the maximum end of each subtree (the "cgranges" layout).
+///
+/// Finding the `k` intervals overlapping a query takes O(log n + k).
+pub(crate) struct IntervalTree<T> {
+    items: Vec<(Interval, T)>,
+    /// Maximum end of the subtree rooted at each node.
+    max_ends: Vec<u64>,
+    /// Height of the root node, or `None` if the tree is empty.
+    max_level: Option<usize>,
+}
+
+impl<T> IntervalTree<T> {
+    pub(crate) fn new(mut items: Vec<(Interval, T)>) -> Self {
+        items.sort_by(|a, b| a.0.cmp(&b.0));
+        let n = items.len();
+        let mut max_ends: Vec<u64> = items.iter().map(|(x, _)| x.end).collect();
+        if n == 0 {
+            return Self {
+                items,
+                max_ends,
+                max_level: None,
+            };
+        }
+        // Leaves are at even positions; the last leaf is carried to fill up incomplete subtrees.
+        let mut last_i = (n - 1) & !1;
+        let mut last = max_ends[last_i];
+        let mut k = 1;
+        while 1 << k <= n {
+            let x = 1 << (k - 1);
+            let step = x << 2;
+            let mut i = (x << 1) - 1;
+            while i < n {
+                let el = max_ends[i - x];
+                let er = if i + x < n { max_ends[i + x] } else { last };
+                max_ends[i] = max_ends[i].max(el).max(er);
+                i += step;
+            }
+            last_i = if (last_i >> k) & 1 == 1 {
+                last_i - x
+            } else {
+                last_i + x
+            };
+            if last_i < n && max_ends[last_i] > last {
+                last = max_ends[last_i];
+            }
+            k += 1;
+        }
+        Self {
+            items,
+            max_ends,
+            max_level: Some(k - 1),
+        }
+    }
+
+    /// Returns the items overlapping `query` with the extent of each overlap, sorted by start.
+    /// An empty query interval is treated as the single base at its start.
+    pub(crate) fn overlapping(&self, query: &Interval) -> Vec<(&T, Overlap)> {
+        let query = Interval::new(query.start, query.end.max(query.start + 1));
+        let (st, en) = (query.start, query.end);
+        let n = self.items.len();
+        let mut result = vec![];
+        let Some(max_level) = self.max_level else {
+            return result;
+        };
+        let mut stack: Vec<(usize, usize, bool)> = vec![((1 << max_level) - 1, max_level, false)];
+        while let Some((x, h, left_done)) = stack.pop() {
+            if h <= 3 {
+                // Small subtree, scanning linearly:
+                let i0 = x >> h << h;
+                let i1 = (i0 + (1 << (h + 1)) - 1).min(n);
+                for (interval, item) in &self.items[i0.min(n)..i1] {
+                    if interval.start >= en {
+                        break;
+                    }
+                    if st < interval.end {
+                        result.push((item, Overlap::new(&query, interval)));
+                    }
+                }
+            } else if !left_done {
+                stack.push((x, h, true));
+                let y = x - (1 << (h - 1));
+                if y >= n || self.max_ends[y] > st {
+                    stack.push((y, h - 1, false));
+                }
+            } else if x < n && self.items[x].0.start < en {
+                let (interval, item) = &self.items[x];
+                if st < interval.end {
+                    result.push((item, Overlap::new(&query, interval)));
+                }
+                stack.push((x + (1 << (h - 1)), h - 1, false));
+            }
+        }
+        result
+    }
+}
+
+/// Genes of one strand sorted by start and by end, to find the closest gene on either side of
+/// a region.
+#[derive(Default)]
+struct Neighbours {
+    /// Genes sorted by start.
+    by_start: Vec<(Interval, String)>,
+    /// Ends of genes with their position in `by_start`, sorted by end.
+    by_end: Vec<(u64, usize)>,
+}
+
+impl Neighbours {
+    fn new(mut genes: Vec<(Interval, String)>) -> Self {
+        genes.sort();
+        let mut by_end: Vec<(u64, usize)> = genes
+            .iter()
+            .enumerate()
+            .map(|(i, (x, _))| (x.end, i))
+            .collect();
+        by_end.sort();
+        Self {
+            by_start: genes,
+            by_end,
+        }
+    }
+
+    /// The gene with the largest end at or before `pos`.
+    fn before(&self, pos: u64) -> Option<&(Interval, String)> {
+        let i = self.by_end.partition_point(|&(end, _)| end <= pos);
+        i.checked_sub(1).map(|i| &self.by_start[self.by_end[i].1])
+    }
+
+    /// The gene with the smallest start at or after `pos`.
+    fn after(&self, pos: u64) -> Option<&(Interval, String)> {
+        let i = self.by_start.partition_point(|(x, _)| x.start < pos);
+        self.by_start.get(i)
+    }
+}
+
+/// Genes of one contig, for finding the closest gene of a region that overlaps none.
+#[derive(Default)]
+pub(crate) struct NearestGenes {
+    plus: Neighbours,
+    /// Genes on the minus strand, whose 5' end is their end coordinate.
+    minus: Neighbours,
+}
+
+impl NearestGenes {
+    /// Collects the genes that may be reported with `options`, named by `gene_name` or
+    /// `gene_id`. Genes are taken from gene features or, in files without any and with
+    /// `options.required_tags` (which are set on transcripts), from the extents of their
+    /// transcripts. Distances are measured to the genes themselves, not to their flanks.
+    pub(crate) fn new(features: &[(Interval, GffLine)], options: &Options) -> Self {
+        let from_transcripts =
+            !options.required_tags.is_empty() || !features.iter().any(|(_, x)| x.is_gene());
+        let mut genes: Vec<(Interval, String, char)> = vec![];
+        let mut transcript_genes: HashMap<(&str, char), Interval> = HashMap::new();
+        for (_, rec) in features {
+            let has_tag = |tag: &String| rec.attribute_values("tag").any(|x| x == tag);
+            let wanted = match from_transcripts {
+                true => rec.is_transcript() && options.required_tags.iter().all(has_tag),
+                false => rec.is_gene(),
+            };
+            if !wanted {
+                continue;
+            }
+            let (name, id) = (rec.attribute("gene_name"), rec.attribute("gene_id"));
+            let Some(gene) = name.or(id) else {
+                continue;
+            };
+            let only_genes = options.only_genes.as_ref();
+            if !only_genes.is_none_or(|genes| crate::is_listed_gene(genes, name, id)) {
+                continue;
+            }
+            if from_transcripts {
+                let extent = transcript_genes
+                    .entry((gene, rec.strand))
+                    .or_insert_with(|| rec.interval.clone());
+                *extent = Interval::new(
+                    extent.start.min(rec.interval.start),
+                    extent.end.max(rec.interval.end),
+                );
+            } else {
+                genes.push((rec.interval.clone(), gene.to_string(), rec.strand));
+            }
+        }
+        let transcript_genes = transcript_genes
+            .into_iter()
+            .map(|((gene, strand), extent)| (extent, gene.to_string(), strand));
+        let (mut plus, mut minus) = (vec![], vec![]);
+        for (interval, gene, strand) in genes.into_iter().chain(transcript_genes) {
+            if strand == '-' {
+                minus.push((interval, gene));
+            } else {
+                plus.push((interval, gene));
+            }
+        }
+        Self {
+            plus: Neighbours::new(plus),
+            minus: Neighbours::new(minus),
+        }
+    }
+
+    /// Returns the closest gene not overlapping `query` with the signed distance to it, in
+    /// bases counted from the gene's nearest end (1 for adjacent genes). The distance is
+    /// negative if the query is upstream of the gene on the gene's strand, and positive if it
+    /// is downstream. `direction` restricts the sign, and `strand` the strands of genes.
+    pub(crate) fn find<'a>(
+        &'a self,
+        query: &Interval,
+        direction: Direction,
+        strand: impl Fn(char) -> bool,
+    ) -> Option<(&'a str, i64)> {
+        let (st, en) = (query.start, query.end.max(query.start + 1));
+        let before = |gene: Option<&'a (Interval, String)>, sign: i64| {
+            gene.map(|(x, name)| (name.as_str(), sign * (st - x.end + 1) as i64))
+        };
+        let after = |gene: Option<&'a (Interval, String)>, sign: i64| {
+            gene.map(|(x, name)| (name.as_str(), sign * (x.start - en + 1) as i64))
+        };
+        let (plus, minus) = (strand('+'), strand('-'));
+        let candidates = [
+            after(self.plus.after(en).filter(|_| plus), -1),
+            before(self.minus.before(st).filter(|_| minus), -1),
+            before(self.plus.before(st).filter(|_| plus), 1),
+            after(self.minus.after(en).filter(|_| minus), 1),
+        ];
+        candidates
+            .into_iter()
+            .flatten()
+            .filter(|(_, d)| match direction {
+                Direction::Any => true,
+                Direction::Upstream => *d < 0,
+                Direction::Downstream => *d > 0,
+            })
+            .min_by_key(|(_, d)| d.unsigned_abs())
+    }
+}
+
+/// Overlap targets of one contig, with the transcript models and genes needed by the
+/// requested fields.
+pub(crate) struct ContigIndex {
+    /// Features keyed by their interval extended with flanks, along with promoters (see
+    /// `push_target`).
+    pub(crate) targets: IntervalTree<GffLine>,
+    /// Transcripts assembled from the features, if region classes or exon and intron numbers
+    /// are reported.
+    pub(crate) models: Option<TranscriptModels>,
+    /// Genes to search with `options.nearest`.
+    pub(crate) nearest_genes: NearestGenes,
+}
+
+impl ContigIndex {
+    pub(crate) fn new(features: Vec<(Interval, GffLine)>, options: &Options) -> Self {
+        let nearest_genes = match options.nearest {
+            Some(_) => NearestGenes::new(&features, options),
+            None => NearestGenes::default(),
+        };
+        let models = options
+            .fields
+            .iter()
+            .any(|x| {
+                matches!(
+                    x,
+                    Field::RegionClass
+                        | Field::RegionClassBases
+                        | Field::ExonNumbers
+                        | Field::IntronNumbers
+                )
+            })
+            .then(|| {
+                let promoter = options.promoter.unwrap_or(region::DEFAULT_PROMOTER);
+                TranscriptModels::new(&features, promoter)
+            });
+        Self {
+            targets: IntervalTree::new(features),
+            models,
+            nearest_genes,
+        }
+    }
+}
+
+/// Annotation features indexed by contig for overlap queries.
+///
+/// ```
+/// let gtf = "chr1\thavana\tgene\t11\t100\t.\t+\t.\tgene_name \"GENE1\";\n";
+/// let index = bedanno::AnnotationIndex::from_reader(gtf.as_bytes(), &Default::default()).unwrap();
+/// assert_eq!(index.gene_names("chr1", 50, 60), vec!["GENE1"]);
+/// assert!(index.gene_names("chr1", 0, 10).is_empty());
+/// ```
+pub struct AnnotationIndex {
+    /// Indexes keyed by normalized contig names, built as `annotate` builds them.
+    contigs: HashMap<String, ContigIndex>,
+    aliases: ContigAliases,
+}
+
+impl AnnotationIndex {
+    /// Reads and indexes all features of a GTF/GFF file, with contig names normalized
+    /// according to `options.aliases`.
+    pub fn from_reader(reader: impl std::io::Read, options: &Options) -> anyhow::Result<Self> {
+        let mut features: HashMap<String, Vec<(Interval, GffLine)>> = HashMap::new();
+        for rec in crate::gff::Reader::new(reader, options.format, options.dialect) {
+            let mut rec = rec?;
+            rec.contig = options.aliases.normalize(&rec.contig).into_owned();
+            let features = features.entry(rec.contig.clone()).or_default();
+            crate::push_target(features, rec, options);
+        }
+        Ok(Self {
+            contigs: features
+                .into_iter()
+                .map(|(contig, features)| (contig, ContigIndex::new(features, options)))
+                .collect(),
+            aliases: options.aliases.clone(),
+        })
+    }
+
+    /// Normalized names of contigs with at least one feature.
+    pub fn contigs(&self) -> impl Iterator<Item = &str> {
+        self.contigs.keys().map(|x| x.as_str())
+    }
+
+    /// Distinct gene names of features overlapping a 0-based half-open region, or within
+    /// the gene flanks of `options`, in the order of feature starts.
+    pub fn gene_names(&self, contig: &str, start: u64, end: u64) -> Vec<&str> {
+        let mut names: Vec<&str> = vec![];
+        let Some(index) = self.contigs.get(self.aliases.normalize(contig).as_ref()) else {
+            return names;
+        };
+        for (rec, _) in index.targets.overlapping(&Interval::new(start, end)) {
+            if let Some(name) = rec.attribute("gene_name") {
+                if !names.contains(&name) {
+                    names.push(name);
+                }
+            }
+        }
+        names
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interval_tree() {
+        // Pseudo-random intervals of various lengths, checked against a linear scan:
+        let mut seed: u64 = 42;
+        let mut rand = |max: u64| {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (seed >> 33) % max
+        };
+        for n in [0, 1, 2, 3, 7, 8, 9, 100, 1000] {
+            let items: Vec<(Interval, usize)> = (0..n)
+                .map(|i| {
+                    let start = rand(10000);
+                    let len = if i % 10 == 0 { rand(5000) } else { rand(50) };
+                    (Interval::new(start, start + len), i)
+                })
+                .collect();
+            let tree = IntervalTree::new(items.clone());
+            for _ in 0..200 {
+                let start = rand(11000);
+                let query = Interval::new(start, start + rand(300));
+                let mut expected: Vec<usize> = items
+                    .iter()
+                    .filter(|(x, _)| {
+                        query.start < x.end && x.start < query.end.max(query.start + 1)
+                    })
+                    .map(|(_, i)| *i)
+                    .collect();
+                let mut found: Vec<usize> = tree
+                    .overlapping(&query)
+                    .into_iter()
+                    .map(|(i, _)| *i)
+                    .collect();
+                expected.sort();
+                found.sort();
+                assert_eq!(expected, found, "n={n}, query={query:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_overlap() {
+        let tree = IntervalTree::new(vec![
+            (Interval::new(100, 200), ()),
+            (Interval::new(0, 1000), ()),
+        ]);
+        let overlaps = tree.overlapping(&Interval::new(150, 250));
+        assert_eq!(overlaps[0].1.bases, 100);
+        assert_eq!(overlaps[0].1.query_fraction, 1.0);
+        assert_eq!(overlaps[0].1.feature_fraction, 0.1);
+        assert_eq!(overlaps[1].1.bases, 50);
+        assert_eq!(overlaps[1].1.query_fraction, 0.5);
+        assert_eq!(overlaps[1].1.feature_fraction, 0.5);
+        // Empty queries cover one base:
+        let overlaps = tree.overlapping(&Interval::new(150, 150));
+        assert_eq!(overlaps[1].1.bases, 1);
+        assert_eq!(overlaps[1].1.query_fraction, 1.0);
+    }
+
+    #[test]
+    fn test_nearest_genes() {
+        let gene = |start, end, strand: &str, name: &str| {
+            let line = format!("1\th\tgene\t{start}\t{end}\t.\t{strand}\t.\tgene_name={name}");
+            let rec = GffLine::from_line(&line, 0, crate::gff::Format::Gff3, None).unwrap();
+            (rec.interval.clone(), rec)
+        };
+        let genes = NearestGenes::new(
+            &[
+                gene(101, 200, "+", "A"),
+                gene(1001, 2000, "-", "B"),
+                gene(2101, 2200, "+", "C"),
+                gene(150, 5000, "+", "LONG"),
+            ],
+            &Options::default(),
+        );
+        let find =
+            |start, end, direction| genes.find(&Interval::new(start, end), direction, |_| true);
+        assert_eq!(find(0, 50, Direction::Any), Some(("A", -51)));
+        assert_eq!(find(300, 400, Direction::Any), Some(("A", 101)));
+        assert_eq!(find(900, 1000, Direction::Any), Some(("B", 1)));
+        assert_eq!(find(900, 1000, Direction::Upstream), Some(("C", -1101)));
+        assert_eq!(find(2000, 2050, Direction::Upstream), Some(("B", -1)));
+        assert_eq!(
+            find(6000, 6000, Direction::Downstream),
+            Some(("LONG", 1001))
+        );
+        assert_eq!(find(0, 50, Direction::Downstream), Some(("B", 951)));
+    }
+
+    #[test]
+    fn test_gene_names() {
+        // A long gene spanning many short ones, so that the tree is deeper than the subtrees
+        // scanned linearly:
+        let mut gtf = "chr1\th\tgene\t1\t1000000\t.\t+\t.\tgene_name=LONG\n".to_string();
+        for i in 0..100 {
+            let start = 1001 + i * 10000;
+            let end = start + 99;
+            gtf.push_str(&format!(
+                "1\th\tgene\t{start}\t{end}\t.\t-\t.\tgene_name=G{i}\n"
+            ));
+        }
+        gtf.push_str("chr1\th\texon\t1001\t1050\t.\t-\t.\tgene_name=G0\n");
+        let options = Options {
+            format: crate::gff::Format::Gff3,
+            ..Default::default()
+        };
+        let index = AnnotationIndex::from_reader(gtf.as_bytes(), &options).unwrap();
+        assert_eq!(index.contigs().collect::<Vec<&str>>(), vec!["1"]);
+        assert_eq!(index.gene_names("chr1", 0, 10), vec!["LONG"]);
+        assert_eq!(index.gene_names("1", 1000, 1010), vec!["LONG", "G0"]);
+        assert_eq!(index.gene_names("chr1", 990000, 990010), vec!["LONG"]);
+        assert_eq!(
+            index.gene_names("chr1", 501000, 521000),
+            vec!["LONG", "G50", "G51"]
+        );
+        assert!(index.gene_names("chr1", 1000000, 1000010).is_empty());
+        assert!(index.gene_names("chr2", 0, 10).is_empty());
+
+        // Gene flanks count as in `annotate`:
+        let options = Options {
+            upstream_flank: 100,
+            ..options
+        };
+        let index = AnnotationIndex::from_reader(gtf.as_bytes(), &options).unwrap();
+        assert_eq!(index.gene_names("chr1", 11100, 11110), vec!["LONG", "G1"]);
+    }
+}
@@ -1,7 +1,30 @@
-use anyhow;
-use sorted_list::SortedList;
-use std::collections::{HashMap, HashSet};
+mod bgzf;
+pub mod binary;
+pub mod contig;
+pub mod dialect;
+pub mod error;
+pub mod gff;
+mod index;
+pub mod priority;
+mod region;
+pub mod stats;
+pub mod tabix;
+pub mod validate;
+
+use contig::ContigAliases;
+use dialect::Dialect;
+use error::{FileKind, ParseError, ParseErrorKind};
+use gff::{Format, GffLine};
+pub use index::AnnotationIndex;
+use index::{ContigIndex, IntervalTree, Overlap};
+use priority::Priority;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::fmt;
 use std::io::{self, BufRead, Write};
+use std::str::FromStr;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
 
 #[derive(Clone, Eq, PartialEq, PartialOrd, Ord, Debug)]
 struct Interval {
@@ -14,311 +37,1127 @@ impl Interval {
         Self { start, end }
     }
 
-    fn overlapping(&self, other: &Self) -> bool {
-        return other.start <= self.start && self.start < other.end
-            || self.start <= other.start && other.start <= self.end;
+    /// Number of bases shared by the two intervals.
+    fn overlap_len(&self, other: &Self) -> u64 {
+        self.end
+            .min(other.end)
+            .saturating_sub(self.start.max(other.start))
     }
 }
 
+/// A BED record as read from the input. The original line is kept verbatim so that
+/// name, score, strand and any custom columns are written back unchanged.
 #[derive(Clone, PartialEq)]
-struct GffLine {
+struct BedRecord {
     contig: String,
     interval: Interval,
-    feature_type: String,
-    annotation: String,
+    /// Strand from column 6, if it is `+` or `-`.
+    strand: Option<char>,
+    line: String,
+    /// Position of the record in the input, used to restore the original order on output.
+    index: usize,
+}
+
+/// Which of the overlapping genes to report for each BED record.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// Report only the gene of the highest-priority overlapping feature.
+    Best,
+    /// Report every distinct overlapping gene, comma-separated, ordered by priority.
+    All,
+}
+
+/// Side of a gene on which to look for the closest gene of a region, relative to the gene's
+/// strand.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Any,
+    /// Only genes that the region is upstream of.
+    Upstream,
+    /// Only genes that the region is downstream of.
+    Downstream,
+}
+
+/// Settings for reporting the closest gene of regions overlapping none.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Nearest {
+    pub direction: Direction,
+    /// Genes further away are not reported.
+    pub max_distance: Option<u64>,
+}
+
+/// Which annotation features may be reported for BED records with a strand in column 6.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Strandedness {
+    /// Features on either strand.
+    Any,
+    /// Only features on the same strand as the record.
+    Same,
+    /// Only features on the opposite strand.
+    Opposite,
+}
+
+impl Strandedness {
+    /// Whether a feature on `strand` may be reported for a record on `record_strand`.
+    fn allows(&self, record_strand: Option<char>, strand: char) -> bool {
+        match (self, record_strand) {
+            (Strandedness::Any, _) | (_, None) => true,
+            (Strandedness::Same, Some(x)) => strand == x,
+            (Strandedness::Opposite, Some(x)) => strand != x && (strand == '+' || strand == '-'),
+        }
+    }
+}
+
+/// A value written in an output column for each reported annotation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Field {
+    /// GTF/GFF feature type (column 3), e.g. `CDS` or `exon`.
+    FeatureType,
+    /// Strand of the annotation feature (column 7).
+    Strand,
+    /// 1-based start of the annotation feature, as written in the GTF/GFF file.
+    Start,
+    /// End of the annotation feature.
+    End,
+    /// Number of bases shared by the BED region and the annotation feature.
+    OverlapBp,
+    /// Fraction of the BED region covered by the annotation feature.
+    OverlapQueryFraction,
+    /// Fraction of the annotation feature covered by the BED region.
+    OverlapFeatureFraction,
+    /// Most severe part of a transcript the BED region falls in: `CDS`, `splice_region`, `5UTR`,
+    /// `3UTR`, `noncoding_exon`, `intron`, `promoter` or `intergenic`. Reported once per region.
+    RegionClass,
+    /// Number of bases of the BED region in each region class, e.g. `CDS:40,intron:60`.
+    RegionClassBases,
+    /// Numbers of the exons of the annotation's transcript overlapped by the BED region,
+    /// counted from the 5' end, e.g. `11` or `10-12`.
+    ExonNumbers,
+    /// Numbers of the introns of the annotation's transcript overlapped by the BED region.
+    IntronNumbers,
+    /// Any attribute from column 9, e.g. `gene_name`, `gene_id` or `exon_number`.
+    Attribute(String),
+}
+
+impl FromStr for Field {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.trim() {
+            "" => return Err(anyhow::anyhow!("Empty output field name")),
+            "feature_type" => Field::FeatureType,
+            "strand" => Field::Strand,
+            "start" => Field::Start,
+            "end" => Field::End,
+            "overlap_bp" => Field::OverlapBp,
+            "overlap_query_frac" => Field::OverlapQueryFraction,
+            "overlap_feature_frac" => Field::OverlapFeatureFraction,
+            "region_class" => Field::RegionClass,
+            "region_class_bases" => Field::RegionClassBases,
+            "exon_numbers" => Field::ExonNumbers,
+            "intron_numbers" => Field::IntronNumbers,
+            name => Field::Attribute(name.to_string()),
+        })
+    }
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Field::FeatureType => write!(f, "feature_type"),
+            Field::Strand => write!(f, "strand"),
+            Field::Start => write!(f, "start"),
+            Field::End => write!(f, "end"),
+            Field::OverlapBp => write!(f, "overlap_bp"),
+            Field::OverlapQueryFraction => write!(f, "overlap_query_frac"),
+            Field::OverlapFeatureFraction => write!(f, "overlap_feature_frac"),
+            Field::RegionClass => write!(f, "region_class"),
+            Field::RegionClassBases => write!(f, "region_class_bases"),
+            Field::ExonNumbers => write!(f, "exon_numbers"),
+            Field::IntronNumbers => write!(f, "intron_numbers"),
+            Field::Attribute(name) => write!(f, "{name}"),
+        }
+    }
+}
+
+/// Parses a comma-separated list of output fields, e.g. `gene_name,gene_id,overlap_bp`.
+pub fn parse_fields(s: &str) -> anyhow::Result<Vec<Field>> {
+    s.split(',').map(Field::from_str).collect()
+}
+
+/// How much progress information to print to stderr.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    /// Print nothing but errors.
+    Quiet,
+    /// Print warnings.
+    Normal,
+    /// Print warnings and progress messages.
+    Verbose,
+}
+
+/// Settings that control how BED records are annotated and written.
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// Write records in the same order as in the input BED file. When disabled, records
+    /// are written sorted by coordinate within each contig.
+    pub keep_order: bool,
+    pub mode: Mode,
+    /// In `Mode::All`, report at most this many genes per record.
+    pub max_genes: Option<usize>,
+    /// Columns appended to each BED record, one per field.
+    pub fields: Vec<Field>,
+    pub verbosity: Verbosity,
+    /// Skip malformed BED and GTF/GFF lines instead of failing, and report how many were skipped.
+    pub lenient: bool,
+    /// Syntax of the annotation file.
+    pub format: Format,
+    /// Attribute naming conventions of the annotation file, detected for each feature if `None`.
+    pub dialect: Option<Dialect>,
+    /// Used to match BED contigs to annotation contigs named differently.
+    pub aliases: ContigAliases,
+    /// Fail if no BED contig is found in the annotation.
+    pub strict: bool,
+    /// Criteria that rank annotations overlapping the same region.
+    pub priority: Priority,
+    /// Report only annotations of these genes, given by name or ID.
+    pub only_genes: Option<HashSet<String>>,
+    /// Report only annotations carrying all of these tags, e.g. `MANE_Select` or `basic`.
+    pub required_tags: Vec<String>,
+    /// Append the name of and signed distance to the closest gene, for regions without
+    /// reported annotations.
+    pub nearest: Option<Nearest>,
+    /// Report regions within this many bases upstream of the start of a gene or transcript
+    /// with a `promoter` feature of the gene.
+    pub promoter: Option<u64>,
+    /// Extend genes upstream by this many bases before looking for overlaps.
+    pub upstream_flank: u64,
+    /// Extend genes downstream by this many bases before looking for overlaps.
+    pub downstream_flank: u64,
+    /// Match annotation strands to BED record strands. Records without a strand are
+    /// annotated with features on either strand.
+    pub strandedness: Strandedness,
+    /// Number of threads annotating contigs while the annotation is read. Output is the same
+    /// for any number of threads.
+    pub threads: usize,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            keep_order: true,
+            mode: Mode::Best,
+            max_genes: None,
+            fields: vec![Field::Attribute("gene_name".to_string())],
+            verbosity: Verbosity::Normal,
+            lenient: false,
+            format: Format::Gtf,
+            dialect: None,
+            aliases: ContigAliases::default(),
+            strict: false,
+            priority: Priority::default(),
+            only_genes: None,
+            required_tags: vec![],
+            nearest: None,
+            promoter: None,
+            upstream_flank: 0,
+            downstream_flank: 0,
+            strandedness: Strandedness::Any,
+            threads: 1,
+        }
+    }
 }
 
+/// A feature overlapping a BED record. Attributes are read from the feature itself, so that
+/// annotating a record copies none of them.
 #[derive(Clone)]
-struct Annotation {
-    interval: Interval,
-    gene_name: Option<String>,
-    feature_type: String,
-    mane: bool,
-    tsl: String,
-    level: u8,
-    transcript_type: String,
-}
-
-impl Annotation {
-    fn from_gff_line(line: &GffLine) -> Self {
-        let mut attributes: HashMap<String, String> = HashMap::new();
-        for attr in line
-            .annotation
-            .split(';')
-            .filter(|x| !x.is_empty())
-        {
-            let mut kv = attr
-                .trim_matches(' ')
-                .split(|x| x == '=' || x == ' ');
-            let key = kv.next().expect(attr).to_string();
-            let value = kv
-                .next()
-                .expect(attr)
-                .trim_matches('"')
-                .to_string();
-            attributes.insert(key, value);
-        }
-        let mane = attributes
-            .get("tag")
-            .map_or(false, |x| x == "MANE_Select");
-        let tsl = attributes
-            .get("transcript_support_level")
-            .map_or("NA".to_string(), |x| x.to_string());
-        let level = attributes
-            .get("level")
-            .map_or(255, |x| x.parse::<u8>().unwrap_or(255));
-        let transcript_type = attributes
-            .get("transcript_type")
-            .map_or("".to_string(), |x| x.to_string());
+struct Annotation<'a> {
+    interval: &'a Interval,
+    feature_type: &'a str,
+    strand: char,
+    overlap: Overlap,
+    line: &'a GffLine,
+}
+
+impl<'a> Annotation<'a> {
+    fn from_gff_line(line: &'a GffLine, overlap: Overlap) -> Self {
         Self {
-            interval: line.interval.clone(),
-            gene_name: attributes.get("gene_name").cloned(),
-            feature_type: line.feature_type.clone(),
-            mane,
-            tsl,
-            level,
-            transcript_type,
+            interval: &line.interval,
+            feature_type: &line.feature_type,
+            strand: line.strand,
+            overlap,
+            line,
+        }
+    }
+
+    /// Whether the gene is listed by name, or by ID with or without version.
+    fn in_genes(&self, genes: &HashSet<String>) -> bool {
+        is_listed_gene(
+            genes,
+            self.attribute("gene_name"),
+            self.attribute("gene_id"),
+        )
+    }
+
+    /// Gene the feature belongs to, by name or, for genes without one, by ID.
+    fn gene(&self) -> Option<&'a str> {
+        self.attribute("gene_name").or(self.attribute("gene_id"))
+    }
+
+    /// ID of the transcript the feature belongs to, as used for transcript models.
+    fn transcript_id(&self) -> Option<&'a str> {
+        self.attribute("transcript_id").or(self.attribute("Parent"))
+    }
+
+    /// First value of an attribute.
+    fn attribute(&self, key: &str) -> Option<&'a str> {
+        self.line.attribute(key)
+    }
+
+    /// All values of an attribute, e.g. every `tag` of a GENCODE feature.
+    fn attribute_values(&self, key: &'a str) -> impl Iterator<Item = &'a str> {
+        self.line.attribute_values(key)
+    }
+
+    fn has_tag(&self, tag: &str) -> bool {
+        self.line.attribute_values("tag").any(|x| x == tag)
+    }
+
+    fn field(&self, field: &Field) -> Option<String> {
+        match field {
+            Field::FeatureType => Some(self.feature_type.to_string()),
+            Field::Strand => Some(self.strand.to_string()),
+            Field::Start => Some((self.interval.start + 1).to_string()),
+            Field::End => Some(self.interval.end.to_string()),
+            Field::OverlapBp => Some(self.overlap.bases.to_string()),
+            Field::OverlapQueryFraction => Some(format!("{:.4}", self.overlap.query_fraction)),
+            Field::OverlapFeatureFraction => Some(format!("{:.4}", self.overlap.feature_fraction)),
+            Field::Attribute(name) => {
+                let values: Vec<&str> = self.line.attribute_values(name).collect();
+                (!values.is_empty()).then(|| values.join(";"))
+            }
+            // Computed from transcript models:
+            Field::RegionClass
+            | Field::RegionClassBases
+            | Field::ExonNumbers
+            | Field::IntronNumbers => None,
         }
     }
 }
 
-impl GffLine {
-    fn from_line(line: &str) -> Self {
+/// Parses the coordinate in the `column`-th (0-based) column of a line.
+pub(crate) fn parse_coord(
+    tokens: &[&str],
+    column: usize,
+    file: FileKind,
+    i: usize,
+) -> Result<u64, ParseError> {
+    let token = tokens.get(column).ok_or_else(|| {
+        ParseError::new(file, i + 1, Some(column + 1), ParseErrorKind::MissingColumn)
+    })?;
+    token.parse::<u64>().map_err(|_| {
+        ParseError::new(
+            file,
+            i + 1,
+            Some(column + 1),
+            ParseErrorKind::InvalidNumber(token.to_string()),
+        )
+    })
+}
+
+/// Whether a BED line carries no record: a comment, a `track` or `browser` header or a blank line.
+pub(crate) fn is_bed_header(line: &str) -> bool {
+    line.starts_with('#')
+        || line.starts_with("track")
+        || line.starts_with("browser")
+        || line.trim().is_empty()
+}
+
+impl BedRecord {
+    /// Parses the `i`-th (0-based) line of a BED file.
+    fn from_line(line: &str, i: usize) -> Result<Self, ParseError> {
+        let line = line.trim_end_matches('\r');
         let tokens: Vec<&str> = line.split('\t').collect::<Vec<&str>>();
-        GffLine {
-            contig: tokens[0].to_string(),
-            interval: Interval::new(
-                tokens[3].parse::<u64>().unwrap() - 1,
-                tokens[4].parse::<u64>().unwrap(),
-            ),
-            feature_type: tokens[2].to_string(),
-            annotation: tokens[8].to_string(),
+        let start = parse_coord(&tokens, 1, FileKind::Bed, i)?;
+        let end = parse_coord(&tokens, 2, FileKind::Bed, i)?;
+        if start > end {
+            return Err(ParseError::new(
+                FileKind::Bed,
+                i + 1,
+                None,
+                ParseErrorKind::InvalidInterval { start, end },
+            ));
         }
+        let strand = tokens
+            .get(5)
+            .and_then(|x| x.chars().next())
+            .filter(|x| *x == '+' || *x == '-');
+        Ok(BedRecord {
+            contig: tokens[0].to_string(),
+            interval: Interval::new(start, end),
+            strand,
+            line: line.to_string(),
+            index: i,
+        })
     }
 }
 
-fn find_overlaps<'a>(
-    queries: &SortedList<Interval, ()>,
-    targets: &'a SortedList<Interval, GffLine>,
-) -> Vec<Vec<&'a GffLine>> {
-    let mut result: Vec<Vec<&GffLine>> = Vec::new();
-
-    let mut targets = targets.values();
-    let mut prev_overlaps: Vec<&GffLine> = vec![];
-
-    let mut trg: Option<&'a GffLine> = None;
-    for q in queries.keys() {
-        // skip targets that are before the query
-        while let Some(rec) = trg.or_else(|| targets.next()) {
-            if rec.interval.end >= q.start {
-                trg = Some(rec);
-                break;
-            }
-            trg = None;
-        }
-        // take overlapping targets
-        let mut new_overlaps = vec![];
-        while let Some(rec) = trg.or_else(|| targets.next()) {
-            trg = None;
-            if q.overlapping(&rec.interval) {
-                new_overlaps.push(rec);
-            } else {
-                trg = Some(rec);
-                break;
-            }
+/// Counts malformed lines skipped in lenient mode.
+#[derive(Default)]
+struct Skipped {
+    count: usize,
+    first: Option<ParseError>,
+}
+
+impl Skipped {
+    /// Records a skippable error in lenient mode, or returns it back otherwise.
+    fn record(&mut self, e: ParseError, lenient: bool) -> Result<(), ParseError> {
+        if !lenient || !e.is_recoverable() {
+            return Err(e);
+        }
+        self.count += 1;
+        if self.first.is_none() {
+            self.first = Some(e);
         }
+        Ok(())
+    }
 
-        result.push(
-            prev_overlaps
-                .iter()
-                .filter(|rec| q.overlapping(&rec.interval))
-                .chain(new_overlaps.iter())
-                .map(|rec| *rec)
-                .collect(),
+    fn report(&self, file: FileKind) {
+        if let Some(first) = &self.first {
+            eprintln!(
+                "Skipped {} malformed {file} line(s), first one: {first}",
+                self.count
+            );
+        }
+    }
+}
+
+/// Whether a gene is listed by name, or by ID with or without version.
+pub(crate) fn is_listed_gene(
+    genes: &HashSet<String>,
+    name: Option<&str>,
+    id: Option<&str>,
+) -> bool {
+    name.is_some_and(|x| genes.contains(x))
+        || id.is_some_and(|x| genes.contains(x))
+        || id
+            .and_then(|x| x.split_once('.'))
+            .is_some_and(|(x, _)| genes.contains(x))
+}
+
+/// Adds an annotation feature to the overlap targets of its contig, keyed by its interval
+/// extended with the gene flanks, along with the promoter of each gene and transcript. The key
+/// only selects candidates: overlaps are measured against the feature's own interval.
+pub(crate) fn push_target(targets: &mut Vec<(Interval, GffLine)>, rec: GffLine, options: &Options) {
+    let minus = rec.strand == '-';
+    if let Some(len) = options
+        .promoter
+        .filter(|_| rec.is_gene() || rec.is_transcript())
+    {
+        let window = if minus {
+            Interval::new(rec.interval.end, rec.interval.end.saturating_add(len))
+        } else {
+            Interval::new(rec.interval.start.saturating_sub(len), rec.interval.start)
+        };
+        if window.start < window.end {
+            let mut promoter = rec.clone();
+            promoter.feature_type = "promoter".to_string();
+            promoter.interval = window.clone();
+            targets.push((window, promoter));
+        }
+    }
+    let mut interval = rec.interval.clone();
+    if rec.is_gene() {
+        let (before, after) = if minus {
+            (options.downstream_flank, options.upstream_flank)
+        } else {
+            (options.upstream_flank, options.downstream_flank)
+        };
+        interval = Interval::new(
+            interval.start.saturating_sub(before),
+            interval.end.saturating_add(after),
         );
-        prev_overlaps = new_overlaps;
     }
-    result
+    targets.push((interval, rec));
 }
 
-fn resolve_all_overlaps<'a>(
-    queries: &'a SortedList<Interval, ()>,
-    gfflines: &'a mut Vec<Vec<&'a GffLine>>,
-) -> Vec<Option<Annotation>> {
-    let feature_type_rank = [
-        "CDS",
-        "stop_codon",
-        "start_codon",
-        "UTR",
-        "exon",
-        "transcript",
-        "gene",
-    ]
-    .iter()
-    .enumerate()
-    .map(|(i, x)| (x.to_owned().to_owned(), i))
-    .collect::<HashMap<String, usize>>();
-
-    let tsl_rank = ["1", "2", "3", "4", "5", "NA"]
+fn find_overlaps<'a>(
+    queries: &[&BedRecord],
+    targets: &'a IntervalTree<GffLine>,
+    strandedness: Strandedness,
+) -> Vec<Vec<(&'a GffLine, Overlap)>> {
+    queries
         .iter()
-        .enumerate()
-        .map(|(i, x)| (x.to_owned().to_owned(), i))
-        .collect::<HashMap<String, usize>>();
+        .map(|q| {
+            targets
+                .overlapping(&q.interval)
+                .into_iter()
+                .filter(|(x, _)| strandedness.allows(q.strand, x.strand))
+                .map(|(x, _)| (x, Overlap::new(&q.interval, &x.interval)))
+                .collect()
+        })
+        .collect()
+}
 
-    queries
-        .keys()
-        .enumerate()
-        .map(|(i, q)| {
-            let mut annotations: Vec<Annotation> = gfflines[i]
+/// Returns annotations overlapping each query, sorted from the highest to the lowest priority.
+fn resolve_all_overlaps<'a>(
+    gfflines: &[Vec<(&'a GffLine, Overlap)>],
+    priority: &Priority,
+) -> Vec<Vec<Annotation<'a>>> {
+    gfflines
+        .iter()
+        .map(|overlaps| {
+            let mut annotations: Vec<Annotation> = overlaps
                 .iter()
-                .map(|&t| Annotation::from_gff_line(t))
+                .map(|&(t, overlap)| Annotation::from_gff_line(t, overlap))
                 .collect();
-            annotations.sort_by_key(|anno| {
-                // chr1    HAVANA  exon    12010   12057   .       +       .       gene_id "ENSG00000223972.6"; transcript_id "ENST00000450305.2"; gene_type "transcribed_unprocessed_pseudogene"; gene_name "DDX11L1"; transcript_type "transcribed_unprocessed_pseudogene"; transcript_name "DDX11L1-201"; exon_number 1; exon_id "ENSE00001948541.1"; level 2; transcript_support_level "NA"; hgnc_id "HGNC:37102"; ont "PGO:0000005"; ont "PGO:0000019"; tag "basic"; tag "Ensembl_canonical"; havana_gene "OTTHUMG00000000961.2"; havana_transcript "OTTHUMT00000002844.2";
-                let feature_type = feature_type_rank
-                    .get(&anno.feature_type)
-                    .unwrap_or(&255);
-                let mane: u8 = if anno.mane { 0 } else { 255 };
-                let tsl = tsl_rank.get(&anno.tsl).unwrap_or(&255);
-                let level = anno.level;
-                let coding: u8 = (anno.transcript_type != "protein_coding") as u8;
-                let overlap = if q.start < anno.interval.start {
-                    q.end - anno.interval.start
-                } else {
-                    anno.interval.end - q.start
-                };
-                (feature_type, mane, tsl, level, coding, overlap)
-            });
-            annotations.first().cloned()
+            annotations.sort_by_cached_key(|anno| priority.key(anno));
+            annotations
         })
-        .collect::<Vec<Option<Annotation>>>()
+        .collect::<Vec<Vec<Annotation>>>()
+}
+
+/// Picks annotations to report from annotations sorted by priority. In `Mode::All`, that is
+/// the highest-priority annotation of each distinct gene, told apart by name or ID. Annotations of genes not in
+/// `options.only_genes` or without the `options.required_tags` are skipped.
+fn select_annotations<'a, 'b>(
+    annotations: &'a [Annotation<'b>],
+    options: &Options,
+) -> Vec<&'a Annotation<'b>> {
+    let mut annotations = annotations.iter().filter(|x| {
+        let genes = &options.only_genes;
+        genes.as_ref().is_none_or(|genes| x.in_genes(genes))
+            && options.required_tags.iter().all(|tag| x.has_tag(tag))
+    });
+    match options.mode {
+        Mode::Best => annotations.next().into_iter().collect(),
+        Mode::All => {
+            let mut selected: Vec<&Annotation> = vec![];
+            for anno in annotations.filter(|x| x.gene().is_some()) {
+                if !selected.iter().any(|x| x.gene() == anno.gene()) {
+                    selected.push(anno);
+                }
+            }
+            if let Some(max_genes) = options.max_genes {
+                selected.truncate(max_genes);
+            }
+            selected
+        }
+    }
+}
+
+/// Counts of annotated regions, reported after a run to diagnose mismatched inputs.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Summary {
+    /// Number of BED records written.
+    pub regions: usize,
+    /// Number of BED records with at least one reported annotation.
+    pub annotated: usize,
+    /// Number of distinct BED contigs.
+    pub contigs: usize,
+    /// BED contigs with no features in the annotation, as named in the BED file.
+    pub missing_contigs: Vec<String>,
+    /// Largest number of annotation features held in memory at once: the features of the BED
+    /// contigs being read and annotated, and the GFF3 features kept to resolve parents.
+    pub peak_features: usize,
+}
+
+impl Summary {
+    fn report(&self) {
+        if !self.missing_contigs.is_empty() {
+            eprintln!(
+                "{} BED contig(s) not found in the annotation: {}",
+                self.missing_contigs.len(),
+                self.missing_contigs.join(", ")
+            );
+        }
+        let unannotated = self.regions - self.annotated;
+        if unannotated > 0 {
+            eprintln!(
+                "{unannotated} of {} region(s) ({:.1}%) have no annotation",
+                self.regions,
+                100.0 * unannotated as f64 / self.regions as f64
+            );
+        }
+    }
 }
 
 pub fn run(
     qreader: impl io::Read,
     treader: impl io::Read,
     writer: impl io::Write,
-) -> anyhow::Result<()> {
-    // let mut treader = gff::Reader::new(treader, gff_type);
+) -> anyhow::Result<Summary> {
+    annotate(qreader, treader, writer, &Options::default())
+}
 
-    let mut qreader = io::BufReader::new(qreader)
-        .lines()
-        .enumerate()
-        .map(|(i, x)| (i, x.expect(&format!("Reading BED line {i}"))))
-        .skip_while(|(_, x)| x.starts_with('#'));
+/// BED records of one contig.
+struct ContigQueries {
+    /// Contig name as written in the BED file.
+    name: String,
+    /// Position of the first record of the contig in the BED file.
+    first_line: usize,
+    /// Records in coordinate order.
+    queries: Vec<BedRecord>,
+}
 
-    let mut treader = io::BufReader::new(treader)
-        .lines()
-        .enumerate()
-        .map(|(i, x)| (i, x.expect(&format!("Reading GTF line {i}"))))
-        .skip_while(|(_, x)| x.starts_with('#'));
-
-    let mut writer = io::BufWriter::new(writer);
-
-    let mut seen_qry_contigs: HashSet<String> = HashSet::new();
-    let mut next_qry_contig = "".to_string();
-    // Buffering into this HashMap until we meet the contig we are looking for:
-    let mut targets_buffer: HashMap<String, SortedList<Interval, GffLine>> = HashMap::new();
-    // Buffering one last BED and GTF record so we can do 2 parallel loops over BED and GTF:
-    let mut buf_qry = None;
-    let mut buf_trg = None;
-    loop {
-        // Contigs loop
-        let mut cur_contig = next_qry_contig.clone();
-        let mut cur_queries: SortedList<Interval, ()> = SortedList::new();
-
-        while let Some((i, line)) = buf_qry.clone().or_else(|| qreader.next()) {
-            buf_qry = None;
-            let tokens = line.split('\t').collect::<Vec<&str>>();
-            let contig = tokens[0];
-            if cur_contig == "" {
-                cur_contig = contig.to_string();
-            }
-            if contig == &cur_contig {
-                let start = tokens[1].parse::<u64>()?;
-                let end = tokens[2].parse::<u64>()?;
-                let interval = Interval::new(start, end);
-                cur_queries.insert(interval, ());
-            } else {
-                // New contig
-                if seen_qry_contigs.contains(contig) {
-                    return Err(anyhow::anyhow!(
-                        "Parsing BED record {i}: contig {} is out of order",
-                        contig
-                    ));
+/// Records annotated at once, so that the annotations of only so many records are held
+/// before their output lines are formatted.
+const BATCH_RECORDS: usize = 4096;
+
+/// Annotates the records of one contig with its annotation features and formats their output
+/// lines.
+fn annotate_contig(
+    contig: &ContigQueries,
+    features: Vec<(Interval, GffLine)>,
+    options: &Options,
+) -> Vec<(usize, String, bool)> {
+    if options.verbosity >= Verbosity::Verbose {
+        eprintln!("Processing contig {}", contig.name);
+    }
+    let index = ContigIndex::new(features, options);
+    let queries: Vec<&BedRecord> = contig.queries.iter().collect();
+    queries
+        .chunks(BATCH_RECORDS)
+        .flat_map(|x| annotate_records(x, &index, options))
+        .collect()
+}
+
+/// Annotation features read from a GTF/GFF or binary annotation file.
+pub(crate) trait Features: Iterator<Item = Result<GffLine, ParseError>> {
+    /// Number of features held by the reader besides the ones it returned, e.g. to resolve
+    /// GFF3 parents.
+    fn held_features(&self) -> usize {
+        0
+    }
+}
+
+/// Writes the output lines of contigs as they are annotated: in the order of the BED file
+/// with `keep_order`, or contig by contig in the order contigs first appear in the BED file.
+/// Lines of a contig are written once all earlier contigs are annotated, so that output is
+/// streamed when contigs come in the same order in the BED and annotation files.
+struct Output<W: io::Write> {
+    writer: io::BufWriter<W>,
+    keep_order: bool,
+    /// Whether lines are held back, e.g. until the run is known not to fail.
+    hold: bool,
+    /// Position of the first record of each contig in the BED file.
+    first_lines: Vec<usize>,
+    /// Output lines of each annotated contig not written yet, with their positions in the
+    /// BED file, in coordinate order.
+    contigs: Vec<Option<Vec<(usize, String)>>>,
+    /// First contig not annotated yet.
+    next: usize,
+    /// With `keep_order`, lines of annotated contigs waiting for the lines of contigs
+    /// intermixed with them in the BED file.
+    pending: BinaryHeap<Reverse<(usize, String)>>,
+}
+
+impl<W: io::Write> Output<W> {
+    fn new(writer: W, contigs: &[ContigQueries], options: &Options) -> Self {
+        Self {
+            writer: io::BufWriter::new(writer),
+            keep_order: options.keep_order,
+            hold: options.strict,
+            first_lines: contigs.iter().map(|x| x.first_line).collect(),
+            contigs: contigs.iter().map(|_| None).collect(),
+            next: 0,
+            pending: BinaryHeap::new(),
+        }
+    }
+
+    fn push(&mut self, idx: usize, lines: Vec<(usize, String)>) -> io::Result<()> {
+        self.contigs[idx] = Some(lines);
+        self.write_ready()
+    }
+
+    /// Writes the lines of annotated contigs that no earlier line is waiting for.
+    fn write_ready(&mut self) -> io::Result<()> {
+        if self.hold {
+            return Ok(());
+        }
+        while let Some(Some(lines)) = self.contigs.get_mut(self.next).map(Option::take) {
+            self.next += 1;
+            for (i, line) in lines {
+                match self.keep_order {
+                    true => self.pending.push(Reverse((i, line))),
+                    false => self.writer.write_all(line.as_bytes())?,
                 }
-                seen_qry_contigs.insert(cur_contig.clone());
-                next_qry_contig = contig.to_owned();
-                buf_qry = Some((i, line.clone()));
+            }
+        }
+        // Lines of later contigs all come after the first line of the next contig:
+        let next_line = self.first_lines.get(self.next).copied();
+        while let Some(Reverse((i, _))) = self.pending.peek() {
+            if next_line.is_some_and(|x| *i >= x) {
                 break;
             }
+            let Some(Reverse((_, line))) = self.pending.pop() else {
+                break;
+            };
+            self.writer.write_all(line.as_bytes())?;
         }
-        if cur_queries.len() == 0 {
-            return Ok(());
+        Ok(())
+    }
+
+    /// Writes the remaining lines, once all contigs are annotated.
+    fn finish(mut self) -> io::Result<()> {
+        self.hold = false;
+        self.write_ready()?;
+        self.writer.flush()
+    }
+}
+
+/// Features of one contig sent to a worker thread.
+type Job = (usize, Vec<(Interval, GffLine)>);
+/// Output lines of one contig sent back from a worker thread.
+type JobOutput = (usize, Vec<(usize, String, bool)>);
+
+/// Annotates contigs as their features are read, on the calling thread or on
+/// `options.threads` worker threads, and writes their output lines.
+struct Annotator<'a, W: io::Write> {
+    contigs: &'a [ContigQueries],
+    options: &'a Options,
+    /// Sends the features of each contig to the worker threads, if any.
+    jobs: Option<mpsc::SyncSender<Job>>,
+    /// Output lines of each contig from the worker threads.
+    results: mpsc::Receiver<JobOutput>,
+    output: Output<W>,
+    /// Number of features of each contig, once all of them are read.
+    features: Vec<Option<usize>>,
+    /// Contigs in the order their features were read.
+    order: Vec<usize>,
+    /// Number of features held by contigs being annotated.
+    pending_features: usize,
+    summary: Summary,
+}
+
+impl<'a, W: io::Write> Annotator<'a, W> {
+    fn new<'scope>(
+        scope: &'scope thread::Scope<'scope, '_>,
+        contigs: &'a [ContigQueries],
+        options: &'a Options,
+        writer: W,
+    ) -> Self
+    where
+        'a: 'scope,
+    {
+        let (result_tx, results) = mpsc::channel();
+        let mut jobs = None;
+        if options.threads > 1 {
+            // Handing each contig to an idle thread, so that the features of at most one
+            // contig more than threads are held at once:
+            let (job_tx, job_rx) = mpsc::sync_channel::<Job>(0);
+            let job_rx = Arc::new(Mutex::new(job_rx));
+            for _ in 0..options.threads {
+                let (job_rx, result_tx) = (job_rx.clone(), result_tx.clone());
+                scope.spawn(move || loop {
+                    let job = job_rx.lock().expect("Annotation thread panicked").recv();
+                    let Ok((idx, features)) = job else { break };
+                    let lines = annotate_contig(&contigs[idx], features, options);
+                    if result_tx.send((idx, lines)).is_err() {
+                        break;
+                    }
+                });
+            }
+            jobs = Some(job_tx);
+        }
+        Self {
+            contigs,
+            options,
+            jobs,
+            results,
+            output: Output::new(writer, contigs, options),
+            features: vec![None; contigs.len()],
+            order: vec![],
+            pending_features: 0,
+            summary: Summary {
+                contigs: contigs.len(),
+                ..Default::default()
+            },
         }
-        eprintln!("Processing contig, {}", cur_contig);
+    }
 
-        // Now that we collected queries for qry_contig, looking for corresponding targets
-        let cur_targets = if targets_buffer.contains_key(&cur_contig) {
-            // If we already recorded current contig before, taking it from the buffer:
-            targets_buffer.remove(&cur_contig).unwrap()
-        } else {
-            // Else searching in the file:
-            let mut res: SortedList<Interval, GffLine> = SortedList::new();
-            while let Some((i, line)) = buf_trg.clone().or_else(|| treader.next()) {
-                buf_trg = None;
+    /// Whether all features of a contig were read.
+    fn is_submitted(&self, idx: usize) -> bool {
+        self.features[idx].is_some()
+    }
 
-                let rec = GffLine::from_line(&line);
+    /// Streams the annotation once, annotating each BED contig as soon as all of its features
+    /// are read. Features of contigs absent from the BED file are not kept, so that at most the
+    /// features of the contigs being annotated are held in memory whatever the order of
+    /// contigs. `contig_index` maps normalized contig names to indices in `contigs`.
+    fn read_features(
+        &mut self,
+        mut features: impl Features,
+        contig_index: &HashMap<String, usize>,
+        skipped: &mut Skipped,
+    ) -> anyhow::Result<()> {
+        let options = self.options;
+        let mut remaining = self.contigs.len();
+        let mut cur_contig: Option<String> = None;
+        let mut cur_targets: Vec<(Interval, GffLine)> = vec![];
+        while let Some(rec) = features.next() {
+            if remaining == 0 {
+                break;
+            }
+            let mut rec = match rec {
+                Ok(rec) => rec,
+                Err(e) => {
+                    skipped.record(e, options.lenient)?;
+                    continue;
+                }
+            };
+            rec.contig = options.aliases.normalize(&rec.contig).into_owned();
 
-                if rec.contig != cur_contig && res.len() > 0 {
-                    buf_trg = Some((i, line));
-                    break; // finished collecting contig data
+            if cur_contig.as_ref() != Some(&rec.contig) {
+                // Finished collecting contig data
+                if let Some(&idx) = cur_contig.as_ref().and_then(|x| contig_index.get(x)) {
+                    self.submit(idx, std::mem::take(&mut cur_targets))?;
+                    remaining -= 1;
                 }
-                if rec.contig != cur_contig {
-                    // Buffering
-                    targets_buffer
-                        .entry(rec.contig.to_owned())
-                        .or_insert(SortedList::new())
-                } else {
-                    &mut res
+                if let Some(&idx) = contig_index.get(&rec.contig) {
+                    if self.is_submitted(idx) {
+                        return Err(anyhow::anyhow!(
+                            "Features of contig {} are not grouped together in the annotation file",
+                            rec.contig
+                        ));
+                    }
                 }
-                .insert(rec.interval.clone(), rec);
+                cur_contig = Some(rec.contig.clone());
+                cur_targets.clear();
             }
-            res
-        };
+            if contig_index.contains_key(&rec.contig) {
+                push_target(&mut cur_targets, rec, options);
+            }
+            let held = self.pending_features + cur_targets.len() + features.held_features();
+            self.summary.peak_features = self.summary.peak_features.max(held);
+        }
+        if let Some(&idx) = cur_contig.as_ref().and_then(|x| contig_index.get(x)) {
+            if !self.is_submitted(idx) {
+                self.submit(idx, cur_targets)?;
+            }
+        }
+        Ok(())
+    }
 
-        let mut annotations = find_overlaps(&mut cur_queries, &cur_targets);
-        let annotations = resolve_all_overlaps(&cur_queries, &mut annotations);
-        for (q, anno) in cur_queries.keys().zip(annotations) {
-            let mut line = String::new();
-            line.push_str(&cur_contig);
-            line.push('\t');
-            line.push_str(&q.start.to_string());
-            line.push('\t');
-            line.push_str(&q.end.to_string());
-            let gene = anno
-                .map_or(".".to_string(), |x| x.gene_name.unwrap_or(".".to_string()))
-                .to_string();
+    /// Annotates a contig once all of its features are read.
+    fn submit(&mut self, idx: usize, features: Vec<(Interval, GffLine)>) -> io::Result<()> {
+        self.features[idx] = Some(features.len());
+        self.order.push(idx);
+        self.pending_features += features.len();
+        self.summary.peak_features = self.summary.peak_features.max(self.pending_features);
+        // `options.strict` fails only when no contig has features:
+        if !features.is_empty() {
+            self.output.hold = false;
+        }
+        match &self.jobs {
+            Some(jobs) => {
+                // Workers only stop early by panicking, which the scope reports:
+                let _ = jobs.send((idx, features));
+                while let Ok((idx, lines)) = self.results.try_recv() {
+                    self.collect(idx, lines)?;
+                }
+                Ok(())
+            }
+            None => {
+                let lines = annotate_contig(&self.contigs[idx], features, self.options);
+                self.collect(idx, lines)
+            }
+        }
+    }
+
+    fn collect(&mut self, idx: usize, lines: Vec<(usize, String, bool)>) -> io::Result<()> {
+        self.pending_features -= self.features[idx].unwrap_or_default();
+        self.summary.regions += lines.len();
+        self.summary.annotated += lines.iter().filter(|x| x.2).count();
+        let lines = lines.into_iter().map(|(i, line, _)| (i, line)).collect();
+        self.output.push(idx, lines)
+    }
+
+    /// Annotates the contigs absent from the annotation and waits for all contigs to be
+    /// annotated. Returns the summary of the run, and the output with the lines not written
+    /// yet.
+    fn finish(mut self) -> io::Result<(Summary, Output<W>)> {
+        for idx in 0..self.contigs.len() {
+            if !self.is_submitted(idx) {
+                self.submit(idx, vec![])?;
+            }
+        }
+        drop(self.jobs.take());
+        while let Ok((idx, lines)) = self.results.recv() {
+            self.collect(idx, lines)?;
+        }
+        for &idx in &self.order {
+            if self.features[idx] == Some(0) {
+                let name = self.contigs[idx].name.clone();
+                self.summary.missing_contigs.push(name);
+            }
+        }
+        Ok((self.summary, self.output))
+    }
+}
+
+/// Annotates BED records of one contig and formats their output lines. Returns the input
+/// position of each record, its line, and whether any annotation was reported.
+fn annotate_records(
+    queries: &[&BedRecord],
+    index: &ContigIndex,
+    options: &Options,
+) -> Vec<(usize, String, bool)> {
+    let models = index.models.as_ref();
+    let annotations = resolve_all_overlaps(
+        &find_overlaps(queries, &index.targets, options.strandedness),
+        &options.priority,
+    );
+    let mut lines = vec![];
+    for (q, annos) in queries.iter().zip(annotations) {
+        let mut line = String::new();
+        line.push_str(&q.line);
+        let selected = select_annotations(&annos, options);
+        let classes = models.map(|x| {
+            x.classify(&q.interval, |strand| {
+                options.strandedness.allows(q.strand, strand)
+            })
+        });
+        for field in &options.fields {
+            if let (Field::RegionClass, Some(classes)) = (field, &classes) {
+                line.push('\t');
+                line.push_str(&classes[0].0.to_string());
+                continue;
+            }
+            if let (Field::RegionClassBases, Some(classes)) = (field, &classes) {
+                let classes = classes
+                    .iter()
+                    .map(|(class, bases)| format!("{class}:{bases}"))
+                    .collect::<Vec<String>>();
+                line.push('\t');
+                line.push_str(&classes.join(","));
+                continue;
+            }
+            let values = selected
+                .iter()
+                .map(|x| match (field, models) {
+                    (Field::ExonNumbers | Field::IntronNumbers, Some(models)) => {
+                        let (exons, introns) = x
+                            .transcript_id()
+                            .map(|id| models.exon_intron_numbers(id, &q.interval))
+                            .unwrap_or_default();
+                        let numbers = if *field == Field::ExonNumbers {
+                            exons
+                        } else {
+                            introns
+                        };
+                        numbers.unwrap_or(".".to_string())
+                    }
+                    _ => x.field(field).unwrap_or(".".to_string()),
+                })
+                .collect::<Vec<String>>();
             line.push('\t');
-            line.push_str(&gene);
-            line.push('\n');
-            writer.write(line.as_bytes())?;
+            line.push_str(&if values.is_empty() {
+                ".".to_string()
+            } else {
+                values.join(",")
+            });
+        }
+        if let Some(nearest) = &options.nearest {
+            let strand = |strand| options.strandedness.allows(q.strand, strand);
+            let gene = index
+                .nearest_genes
+                .find(&q.interval, nearest.direction, strand)
+                .filter(|_| selected.is_empty())
+                .filter(|(_, d)| nearest.max_distance.is_none_or(|x| d.unsigned_abs() <= x));
+            match gene {
+                Some((name, distance)) => line.push_str(&format!("\t{name}\t{distance}")),
+                None => line.push_str("\t.\t."),
+            }
         }
+        line.push('\n');
+        lines.push((q.index, line, !selected.is_empty()));
+    }
+    lines
+}
+
+/// Annotates BED records read from `qreader` with the features of the GTF/GFF file read from
+/// `treader`, and writes them to `writer`.
+pub fn annotate(
+    qreader: impl io::Read,
+    treader: impl io::Read,
+    writer: impl io::Write,
+    options: &Options,
+) -> anyhow::Result<Summary> {
+    annotate_features(qreader, writer, options, |queries| {
+        let wanted = queries.into_keys().collect();
+        Ok(gff::Reader::new(treader, options.format, options.dialect)
+            .with_contigs(wanted, options.aliases.clone()))
+    })
+}
+
+/// Like `annotate`, but reads features from a binary annotation file written by
+/// `binary::write_index`. Only the features near the BED records are decoded, along with every
+/// feature within their extent, so that whole genes and transcripts are known. With
+/// `options.nearest`, every gene and transcript is decoded too.
+pub fn annotate_index(
+    qreader: impl io::Read,
+    ireader: impl io::Read + io::Seek,
+    writer: impl io::Write,
+    options: &Options,
+) -> anyhow::Result<Summary> {
+    annotate_features(qreader, writer, options, |queries| {
+        binary::Reader::new(
+            ireader,
+            feature_windows(queries, options),
+            options.nearest.is_some(),
+            &options.aliases,
+        )
+    })
+}
+
+/// Extends the intervals of BED records by the distance within which features can be
+/// reported for them: gene flanks, promoters and splice regions.
+fn feature_windows(
+    queries: HashMap<String, Vec<Interval>>,
+    options: &Options,
+) -> HashMap<String, Vec<Interval>> {
+    let padding = options.upstream_flank.max(options.downstream_flank)
+        + options.promoter.unwrap_or(region::DEFAULT_PROMOTER)
+        + region::SPLICE_REGION;
+    queries
+        .into_iter()
+        .map(|(contig, queries)| {
+            let queries = queries
+                .into_iter()
+                .map(|x| Interval::new(x.start.saturating_sub(padding), x.end + padding))
+                .collect();
+            (contig, queries)
+        })
+        .collect()
+}
+
+/// Like `annotate`, but reads features from a bgzipped GTF/GFF file with a tabix or CSI
+/// index, decompressing only the blocks holding features near the BED records. Features
+/// overlapping the BED records are read along with every feature within their extent, so that
+/// whole genes and transcripts are known. With `options.nearest`, whole contigs are read.
+pub fn annotate_tabix(
+    qreader: impl io::Read,
+    treader: impl io::Read + io::Seek,
+    index: &tabix::Index,
+    writer: impl io::Write,
+    options: &Options,
+) -> anyhow::Result<Summary> {
+    annotate_features(qreader, writer, options, |queries| {
+        // Whole contigs, mapped to `None`, are needed to find nearest genes:
+        let mut intervals = feature_windows(queries, options)
+            .into_iter()
+            .map(|(contig, x)| (contig, options.nearest.is_none().then_some(x)))
+            .collect();
+        let reader = bgzf::Reader::new(treader);
+        let reader = tabix::add_feature_extents(reader, index, &mut intervals, &options.aliases)?;
+        let chunks = index.chunks(&intervals, &options.aliases);
+        let reader = tabix::ChunkReader::new(reader, chunks);
+        let wanted = intervals.into_keys().collect();
+        Ok(gff::Reader::new(reader, options.format, options.dialect)
+            .with_contigs(wanted, options.aliases.clone()))
+    })
+}
+
+/// Annotates BED records with the features returned by `features`, which is given the
+/// intervals of the BED records of each contig, by normalized contig name, once the BED
+/// file is read.
+fn annotate_features<F, I>(
+    qreader: impl io::Read,
+    writer: impl io::Write,
+    options: &Options,
+    features: F,
+) -> anyhow::Result<Summary>
+where
+    F: FnOnce(HashMap<String, Vec<Interval>>) -> anyhow::Result<I>,
+    I: Features,
+{
+    let mut skipped_qry = Skipped::default();
+    let mut skipped_trg = Skipped::default();
+
+    // Reading all BED records up front, grouped by contig in the order contigs first appear,
+    // so that BED contigs can come in any order and be intermixed:
+    let mut contigs: Vec<ContigQueries> = vec![];
+    let mut contig_index: HashMap<String, usize> = HashMap::new();
+    let qreader = io::BufReader::new(qreader)
+        .lines()
+        .enumerate()
+        .filter(|(_, x)| !x.as_ref().is_ok_and(|x| is_bed_header(x)));
+    for (i, line) in qreader {
+        let line =
+            line.map_err(|e| ParseError::new(FileKind::Bed, i + 1, None, ParseErrorKind::Io(e)))?;
+        let mut rec = match BedRecord::from_line(&line, i) {
+            Ok(rec) => rec,
+            Err(e) => {
+                skipped_qry.record(e, options.lenient)?;
+                continue;
+            }
+        };
+        let name = rec.contig.clone();
+        rec.contig = options.aliases.normalize(&rec.contig).into_owned();
+        let idx = *contig_index.entry(rec.contig.clone()).or_insert_with(|| {
+            contigs.push(ContigQueries {
+                name,
+                first_line: rec.index,
+                queries: vec![],
+            });
+            contigs.len() - 1
+        });
+        contigs[idx].queries.push(rec);
+    }
+    for contig in &mut contigs {
+        contig.queries.sort_by(|a, b| a.interval.cmp(&b.interval));
+    }
+
+    let queries = contig_index
+        .iter()
+        .map(|(contig, &idx)| {
+            let queries = contigs[idx].queries.iter();
+            (
+                contig.clone(),
+                queries.map(|x| x.interval.clone()).collect(),
+            )
+        })
+        .collect();
+    let features = features(queries)?;
+    let (summary, output) = thread::scope(|s| {
+        let mut annotator = Annotator::new(s, &contigs, options, writer);
+        annotator.read_features(features, &contig_index, &mut skipped_trg)?;
+        anyhow::Ok(annotator.finish()?)
+    })?;
+
+    if options.verbosity >= Verbosity::Normal {
+        skipped_qry.report(FileKind::Bed);
+        skipped_trg.report(FileKind::Annotation);
+        summary.report();
     }
+    if options.verbosity >= Verbosity::Verbose {
+        eprintln!(
+            "Held at most {} annotation features in memory",
+            summary.peak_features
+        );
+    }
+    // Output is held back with `options.strict` until a contig has features, so that no
+    // partial output is left behind:
+    if options.strict && summary.contigs > 0 && summary.missing_contigs.len() == summary.contigs {
+        return Err(anyhow::anyhow!(
+            "None of the BED contigs were found in the annotation; check contig names or use --contig-aliases"
+        ));
+    }
+
+    output.finish()?;
+    Ok(summary)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::cell::RefCell;
 
     fn to_str(lines: &[&str]) -> String {
         lines
-            .into_iter()
-            .map(|x| {
-                x.split_whitespace()
-                    .collect::<Vec<&str>>()
-                    .join("\t")
-            })
+            .iter()
+            .map(|x| x.split_whitespace().collect::<Vec<&str>>().join("\t"))
             .collect::<Vec<String>>()
             .join("\n")
     }
@@ -405,4 +1244,1065 @@ mod tests {
         let output = String::from_utf8(output).unwrap();
         assert_eq!(&expected.trim(), &output.trim())
     }
+
+    #[test]
+    fn test_extra_bed_columns() {
+        let queries = to_str(&[
+            "chr1  10  50  target1  0  +  10  50  0  1  40  0",
+            "chr1  400 500 target2  0  -",
+        ]);
+        let targets = to_str(&["chr1  havana gene 21   60   . + . gene_name=GENE1;"]);
+        let expected = to_str(&[
+            "chr1  10  50  target1  0  +  10  50  0  1  40  0  GENE1",
+            "chr1  400 500 target2  0  -  .",
+        ]);
+        let mut output = vec![];
+
+        run(
+            Box::new(queries.as_bytes()),
+            Box::new(targets.as_bytes()),
+            Box::new(&mut output),
+        )
+        .expect("Cannot annotate BED file");
+
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(&expected.trim(), &output.trim())
+    }
+
+    #[test]
+    fn test_keep_order() {
+        let queries = to_str(&[
+            "chr1  400 500 c",
+            "chr1  10  50  a",
+            "chr1  10  50  a",
+            "chr1  100 150 b",
+            "chr2  5   55  d",
+        ]);
+        let targets = to_str(&[
+            "chr1  havana gene 21   60   . + . gene_name=GENE1;",
+            "chr2  havana gene 1    500  . + . gene_name=GENE2;",
+        ]);
+        let annotate_with = |keep_order| {
+            let mut output = vec![];
+            annotate(
+                Box::new(queries.as_bytes()),
+                Box::new(targets.as_bytes()),
+                Box::new(&mut output),
+                &Options {
+                    keep_order,
+                    ..Default::default()
+                },
+            )
+            .expect("Cannot annotate BED file");
+            String::from_utf8(output).unwrap()
+        };
+
+        let expected = to_str(&[
+            "chr1  400 500 c  .",
+            "chr1  10  50  a  GENE1",
+            "chr1  10  50  a  GENE1",
+            "chr1  100 150 b  .",
+            "chr2  5   55  d  GENE2",
+        ]);
+        assert_eq!(&expected.trim(), &annotate_with(true).trim());
+
+        let expected = to_str(&[
+            "chr1  10  50  a  GENE1",
+            "chr1  10  50  a  GENE1",
+            "chr1  100 150 b  .",
+            "chr1  400 500 c  .",
+            "chr2  5   55  d  GENE2",
+        ]);
+        assert_eq!(&expected.trim(), &annotate_with(false).trim());
+    }
+
+    #[test]
+    fn test_all_genes() {
+        let queries = to_str(&["chr1  10  500", "chr1  600 700"]);
+        let targets = to_str(&[
+            "chr1  havana gene 1    100  . + . gene_name=GENE1;",
+            "chr1  havana exon 21   60   . + . gene_name=GENE1;",
+            "chr1  havana gene 51   300  . + . gene_name=GENE2;",
+            "chr1  havana CDS  91   170  . + . gene_name=GENE3;",
+        ]);
+        let annotate_with = |max_genes| {
+            let mut output = vec![];
+            annotate(
+                Box::new(queries.as_bytes()),
+                Box::new(targets.as_bytes()),
+                Box::new(&mut output),
+                &Options {
+                    mode: Mode::All,
+                    max_genes,
+                    ..Default::default()
+                },
+            )
+            .expect("Cannot annotate BED file");
+            String::from_utf8(output).unwrap()
+        };
+
+        let expected = to_str(&["chr1  10  500 GENE3,GENE1,GENE2", "chr1  600 700 ."]);
+        assert_eq!(&expected.trim(), &annotate_with(None).trim());
+
+        let expected = to_str(&["chr1  10  500 GENE3,GENE1", "chr1  600 700 ."]);
+        assert_eq!(&expected.trim(), &annotate_with(Some(2)).trim());
+    }
+
+    #[test]
+    fn test_all_genes_without_names() {
+        let queries = to_str(&["chr1  10  500", "chr1  600 700"]);
+        let targets = to_str(&[
+            "chr1  havana gene 1    100  . + . gene_id=ENSG1;gene_name=GENE1;",
+            "chr1  havana gene 51   300  . + . gene_id=ENSG2;",
+            "chr1  havana exon 61   80   . + . gene_id=ENSG2;",
+            "chr1  havana gene 551  800  . + . gene_id=ENSG3;",
+        ]);
+        let mut output = vec![];
+        let summary = annotate(
+            queries.as_bytes(),
+            targets.as_bytes(),
+            &mut output,
+            &Options {
+                mode: Mode::All,
+                fields: parse_fields("gene_id,gene_name").unwrap(),
+                ..Default::default()
+            },
+        )
+        .expect("Cannot annotate BED file");
+
+        let expected = to_str(&[
+            "chr1  10  500 ENSG2,ENSG1 .,GENE1",
+            "chr1  600 700 ENSG3       .",
+        ]);
+        assert_eq!(&expected.trim(), &String::from_utf8(output).unwrap().trim());
+        assert_eq!(summary.annotated, 2);
+    }
+
+    #[test]
+    fn test_fields() {
+        let queries = to_str(&["chr1  10  50", "chr1  600 700"]);
+        let targets = to_str(&[
+            "chr1  havana gene 1    100  . - . gene_name=GENE1;gene_id=ID1;",
+            "chr1  havana exon 21   60   . - . gene_name=GENE1;gene_id=ID1;exon_number=2;",
+        ]);
+        let expected = to_str(&[
+            "chr1  10  50  GENE1  ID1  exon  -  2  30",
+            "chr1  600 700 .      .    .     .  .  .",
+        ]);
+        let mut output = vec![];
+
+        annotate(
+            Box::new(queries.as_bytes()),
+            Box::new(targets.as_bytes()),
+            Box::new(&mut output),
+            &Options {
+                fields: parse_fields(
+                    "gene_name,gene_id,feature_type,strand,exon_number,overlap_bp",
+                )
+                .unwrap(),
+                ..Default::default()
+            },
+        )
+        .expect("Cannot annotate BED file");
+
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(&expected.trim(), &output.trim())
+    }
+
+    #[test]
+    fn test_overlap_ranking() {
+        let queries = to_str(&["chr1  100 300"]);
+        let targets = to_str(&[
+            "chr1  havana gene 1    1000 . + . gene_name=LONG;",
+            "chr1  havana gene 251  400  . + . gene_name=PARTIAL;",
+            "chr1  havana gene 151  250  . + . gene_name=INSIDE;",
+        ]);
+        let expected = to_str(&[
+            "chr1  100 300  LONG,INSIDE,PARTIAL  200,100,50  1.0000,0.5000,0.2500  0.2000,1.0000,0.3333",
+        ]);
+        let mut output = vec![];
+
+        annotate(
+            Box::new(queries.as_bytes()),
+            Box::new(targets.as_bytes()),
+            Box::new(&mut output),
+            &Options {
+                mode: Mode::All,
+                fields: parse_fields(
+                    "gene_name,overlap_bp,overlap_query_frac,overlap_feature_frac",
+                )
+                .unwrap(),
+                ..Default::default()
+            },
+        )
+        .expect("Cannot annotate BED file");
+
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(&expected.trim(), &output.trim())
+    }
+
+    #[test]
+    fn test_priority() {
+        let queries = to_str(&["chr1  100 300"]);
+        let targets = to_str(&[
+            "chr1  havana CDS  1    1000 . + . gene_name=LNC;gene_type=lncRNA;",
+            "chr1  havana gene 1    1000 . + . gene_name=OTHER;gene_type=protein_coding;",
+            "chr1  havana gene 1    1000 . + . gene_name=PANEL;gene_type=protein_coding;",
+        ]);
+        let annotate_with = |priority: &str| {
+            let mut output = vec![];
+            annotate(
+                Box::new(queries.as_bytes()),
+                Box::new(targets.as_bytes()),
+                Box::new(&mut output),
+                &Options {
+                    mode: Mode::All,
+                    priority: priority.parse().unwrap(),
+                    ..Default::default()
+                },
+            )
+            .expect("Cannot annotate BED file");
+            String::from_utf8(output).unwrap()
+        };
+
+        let expected = to_str(&["chr1  100 300  LNC,OTHER,PANEL"]);
+        assert_eq!(
+            &expected.trim(),
+            &annotate_with("feature_type; overlap").trim()
+        );
+        let expected = to_str(&["chr1  100 300  OTHER,PANEL,LNC"]);
+        assert_eq!(
+            &expected.trim(),
+            &annotate_with("gene_type; feature_type").trim()
+        );
+        let expected = to_str(&["chr1  100 300  PANEL,LNC,OTHER"]);
+        assert_eq!(
+            &expected.trim(),
+            &annotate_with("genes PANEL; feature_type").trim()
+        );
+    }
+
+    #[test]
+    fn test_only_genes() {
+        let queries = to_str(&["chr1  100 300", "chr1  2000 2100"]);
+        let targets = to_str(&[
+            "chr1  havana CDS  1    1000 . - . gene_name=BRCA2-AS;gene_id=ENSG02.1;",
+            "chr1  havana gene 1    1000 . + . gene_name=BRCA2;gene_id=ENSG01.15;",
+            "chr1  havana gene 1901 2200 . + . gene_name=OTHER;gene_id=ENSG03.2;",
+        ]);
+        let expected = to_str(&["chr1  100 300  BRCA2", "chr1  2000 2100  ."]);
+        let genes = priority::read_gene_list("# panel\nENSG01\n".as_bytes()).unwrap();
+        let mut output = vec![];
+
+        annotate(
+            Box::new(queries.as_bytes()),
+            Box::new(targets.as_bytes()),
+            Box::new(&mut output),
+            &Options {
+                only_genes: Some(genes),
+                ..Default::default()
+            },
+        )
+        .expect("Cannot annotate BED file");
+
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(&expected.trim(), &output.trim())
+    }
+
+    #[test]
+    fn test_nearest() {
+        let queries = to_str(&[
+            "chr1  10  50",
+            "chr1  150 160",
+            "chr1  400 500",
+            "chr2  10  20",
+        ]);
+        let targets = to_str(&[
+            "chr1  havana gene 101  200  . - . gene_name=MINUS;",
+            "chr1  havana exon 101  120  . - . gene_name=MINUS;",
+            "chr1  havana gene 1101 1200 . + . gene_name=PLUS;",
+            "chr2  havana gene 10001 10100 . + . gene_name=FAR;",
+        ]);
+        let expected = to_str(&[
+            "chr1  10  50   .      .     .",
+            "chr1  150 160  MINUS  .     .",
+            "chr1  400 500  .      MINUS -201",
+            "chr2  10  20   .      .     .",
+        ]);
+        let mut output = vec![];
+
+        annotate(
+            Box::new(queries.as_bytes()),
+            Box::new(targets.as_bytes()),
+            Box::new(&mut output),
+            &Options {
+                nearest: Some(Nearest {
+                    direction: Direction::Upstream,
+                    max_distance: Some(1000),
+                }),
+                ..Default::default()
+            },
+        )
+        .expect("Cannot annotate BED file");
+
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(&expected.trim(), &output.trim())
+    }
+
+    #[test]
+    fn test_nearest_filters() {
+        let queries = to_str(&[
+            "chr1  500  510   q1  0  +",
+            "chr1  1500 1510  q2  0  +",
+            "chr1  2500 2510  q3  0  +",
+        ]);
+        // No gene lines, so genes span their transcripts:
+        let targets = to_str(&[
+            "chr1  havana transcript 101  200  . + . gene_name=A;transcript_id=A1;",
+            "chr1  havana transcript 151  300  . + . gene_name=A;transcript_id=A2;tag=basic;",
+            "chr1  havana transcript 1001 1100 . - . gene_name=B;transcript_id=B1;tag=basic;",
+            "chr1  havana transcript 2001 2100 . + . gene_name=C;transcript_id=C1;",
+        ]);
+        let annotate_with = |options: Options| {
+            let mut output = vec![];
+            annotate(
+                Box::new(queries.as_bytes()),
+                Box::new(targets.as_bytes()),
+                Box::new(&mut output),
+                &Options {
+                    fields: vec![],
+                    nearest: Some(Nearest {
+                        direction: Direction::Any,
+                        max_distance: None,
+                    }),
+                    ..options
+                },
+            )
+            .expect("Cannot annotate BED file");
+            let output = String::from_utf8(output).unwrap();
+            output
+                .lines()
+                .map(|x| x.split('\t').skip(6).collect::<Vec<&str>>().join(" "))
+                .collect::<Vec<String>>()
+        };
+
+        assert_eq!(
+            annotate_with(Options::default()),
+            ["A 201", "B -401", "C 401"]
+        );
+        assert_eq!(
+            annotate_with(Options {
+                strandedness: Strandedness::Opposite,
+                ..Default::default()
+            }),
+            ["B 491", "B -401", "B -1401"]
+        );
+        assert_eq!(
+            annotate_with(Options {
+                required_tags: vec!["basic".to_string()],
+                ..Default::default()
+            }),
+            ["A 201", "B -401", "B -1401"]
+        );
+        assert_eq!(
+            annotate_with(Options {
+                only_genes: Some(HashSet::from(["A".to_string()])),
+                ..Default::default()
+            }),
+            ["A 201", "A 1201", "A 2201"]
+        );
+    }
+
+    #[test]
+    fn test_promoter() {
+        let queries = to_str(&[
+            "chr1  950 960",
+            "chr1  1500 1510",
+            "chr1  2050 2060",
+            "chr1  2950 2960",
+        ]);
+        let targets = to_str(&[
+            "chr1  havana gene 1001 2000 . + . gene_name=PLUS;",
+            "chr1  havana gene 2101 2900 . - . gene_name=MINUS;",
+        ]);
+        let expected = to_str(&[
+            "chr1  950 960    PLUS   promoter  901   1000",
+            "chr1  1500 1510  PLUS   gene      1001  2000",
+            "chr1  2050 2060  MINUS  gene      2101  2900",
+            "chr1  2950 2960  MINUS  promoter  2901  3000",
+        ]);
+        let mut output = vec![];
+
+        annotate(
+            Box::new(queries.as_bytes()),
+            Box::new(targets.as_bytes()),
+            Box::new(&mut output),
+            &Options {
+                fields: parse_fields("gene_name,feature_type,start,end").unwrap(),
+                promoter: Some(100),
+                downstream_flank: 100,
+                ..Default::default()
+            },
+        )
+        .expect("Cannot annotate BED file");
+
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(&expected.trim(), &output.trim())
+    }
+
+    #[test]
+    fn test_flank_overlap() {
+        let queries = to_str(&["chr1  1990 2050", "chr1  2050 2060", "chr1  2500 2510"]);
+        let targets = to_str(&["chr1  havana gene 1001 2000 . + . gene_name=PLUS;"]);
+        let expected = to_str(&[
+            "chr1  1990 2050  PLUS  1001  2000  10  0.1667  0.0100  .     .",
+            "chr1  2050 2060  PLUS  1001  2000  0   0.0000  0.0000  .     .",
+            "chr1  2500 2510  .     .     .     .   .       .       PLUS  501",
+        ]);
+        let mut output = vec![];
+
+        annotate(
+            Box::new(queries.as_bytes()),
+            Box::new(targets.as_bytes()),
+            Box::new(&mut output),
+            &Options {
+                fields: parse_fields(
+                    "gene_name,start,end,overlap_bp,overlap_query_frac,overlap_feature_frac",
+                )
+                .unwrap(),
+                downstream_flank: 100,
+                nearest: Some(Nearest {
+                    direction: Direction::Any,
+                    max_distance: None,
+                }),
+                ..Default::default()
+            },
+        )
+        .expect("Cannot annotate BED file");
+
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(&expected.trim(), &output.trim())
+    }
+
+    #[test]
+    fn test_strandedness() {
+        let queries = to_str(&[
+            "chr1  100 200  peak1  0  +",
+            "chr1  100 200  peak2  0  -",
+            "chr1  100 200  peak3  0  .",
+            "chr1  100 200",
+        ]);
+        let targets = to_str(&[
+            "chr1  havana CDS   1  1000 . + . gene_name=PLUS;",
+            "chr1  havana gene  1  1000 . - . gene_name=MINUS;",
+        ]);
+        let annotate_with = |strandedness| {
+            let mut output = vec![];
+            annotate(
+                Box::new(queries.as_bytes()),
+                Box::new(targets.as_bytes()),
+                Box::new(&mut output),
+                &Options {
+                    strandedness,
+                    ..Default::default()
+                },
+            )
+            .expect("Cannot annotate BED file");
+            String::from_utf8(output).unwrap()
+        };
+
+        let expected = to_str(&[
+            "chr1  100 200  peak1  0  +  PLUS",
+            "chr1  100 200  peak2  0  -  MINUS",
+            "chr1  100 200  peak3  0  .  PLUS",
+            "chr1  100 200  PLUS",
+        ]);
+        assert_eq!(&expected.trim(), &annotate_with(Strandedness::Same).trim());
+        let expected = to_str(&[
+            "chr1  100 200  peak1  0  +  MINUS",
+            "chr1  100 200  peak2  0  -  PLUS",
+            "chr1  100 200  peak3  0  .  PLUS",
+            "chr1  100 200  PLUS",
+        ]);
+        assert_eq!(
+            &expected.trim(),
+            &annotate_with(Strandedness::Opposite).trim()
+        );
+    }
+
+    #[test]
+    fn test_region_class() {
+        let queries = to_str(&["chr1  1040 1050", "chr1  1095 1150", "chr1  5000 5010"]);
+        let targets = to_str(&[
+            "chr1  havana transcript 1001 1300 . + . gene_name=G1;transcript_id=T1;",
+            "chr1  havana exon       1001 1100 . + . gene_name=G1;transcript_id=T1;",
+            "chr1  havana CDS        1041 1100 . + . gene_name=G1;transcript_id=T1;",
+            "chr1  havana exon       1201 1300 . + . gene_name=G1;transcript_id=T1;",
+        ]);
+        let expected = to_str(&[
+            "chr1  1040 1050  G1  CDS            CDS:10",
+            "chr1  1095 1150  G1  CDS            CDS:5,splice_region:8,intron:42",
+            "chr1  5000 5010  .   intergenic     intergenic:10",
+        ]);
+        let mut output = vec![];
+
+        annotate(
+            Box::new(queries.as_bytes()),
+            Box::new(targets.as_bytes()),
+            Box::new(&mut output),
+            &Options {
+                fields: parse_fields("gene_name,region_class,region_class_bases").unwrap(),
+                ..Default::default()
+            },
+        )
+        .expect("Cannot annotate BED file");
+
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(&expected.trim(), &output.trim())
+    }
+
+    #[test]
+    fn test_exon_numbers() {
+        let queries = to_str(&["chr1  1050 1250", "chr1  1150 1160", "chr1  5000 5010"]);
+        let targets = to_str(&[
+            "chr1  havana transcript 1001 1300 . - . gene_name=G1;transcript_id=T1;",
+            "chr1  havana exon       1001 1100 . - . gene_name=G1;transcript_id=T1;exon_number=2;",
+            "chr1  havana exon       1201 1300 . - . gene_name=G1;transcript_id=T1;exon_number=1;",
+        ]);
+        let expected = to_str(&[
+            "chr1  1050 1250  T1  1-2  1",
+            "chr1  1150 1160  T1  .    1",
+            "chr1  5000 5010  .   .    .",
+        ]);
+        let mut output = vec![];
+
+        annotate(
+            Box::new(queries.as_bytes()),
+            Box::new(targets.as_bytes()),
+            Box::new(&mut output),
+            &Options {
+                fields: parse_fields("transcript_id,exon_numbers,intron_numbers").unwrap(),
+                ..Default::default()
+            },
+        )
+        .expect("Cannot annotate BED file");
+
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(&expected.trim(), &output.trim())
+    }
+
+    #[test]
+    fn test_tags() {
+        let queries = to_str(&["chr1  100 200"]);
+        let targets = [
+            "chr1\tHAVANA\texon\t1\t1000\t.\t+\t.\tgene_name \"G1\"; transcript_id \"T1\"; tag \"basic\"; tag \"CCDS\";",
+            "chr1\tHAVANA\texon\t1\t1000\t.\t+\t.\tgene_name \"G1\"; transcript_id \"T2\"; tag \"basic\"; tag \"Ensembl_canonical\"; tag \"MANE_Select\";",
+            "chr1\tHAVANA\texon\t1\t1000\t.\t+\t.\tgene_name \"G2\"; transcript_id \"T3\"; tag \"readthrough_transcript\";",
+        ]
+        .join("\n");
+        let annotate_with = |options: Options| {
+            let mut output = vec![];
+            annotate(
+                Box::new(queries.as_bytes()),
+                Box::new(targets.as_bytes()),
+                Box::new(&mut output),
+                &Options {
+                    fields: parse_fields("transcript_id,tag").unwrap(),
+                    ..options
+                },
+            )
+            .expect("Cannot annotate BED file");
+            String::from_utf8(output).unwrap()
+        };
+
+        // MANE is found even though it is not the first tag:
+        let expected = to_str(&["chr1  100 200  T2  basic;Ensembl_canonical;MANE_Select"]);
+        assert_eq!(&expected.trim(), &annotate_with(Options::default()).trim());
+        let expected = to_str(&["chr1  100 200  T1  basic;CCDS"]);
+        let options = Options {
+            priority: "tag CCDS".parse().unwrap(),
+            ..Default::default()
+        };
+        assert_eq!(&expected.trim(), &annotate_with(options).trim());
+        let expected = to_str(&["chr1  100 200  T3  readthrough_transcript"]);
+        let options = Options {
+            required_tags: vec!["readthrough_transcript".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(&expected.trim(), &annotate_with(options).trim());
+    }
+
+    #[test]
+    fn test_lenient() {
+        let queries = to_str(&["chr1  10  50", "chr1  abc 60", "chr1  100 150"]);
+        let targets = to_str(&[
+            "chr1  havana gene 21   60   . + . gene_name=GENE1;",
+            "chr1  havana gene 0    60   . + . gene_name=BAD;",
+            "chr1  havana gene 91   170  . + .",
+            "chr1  havana gene 91   170  . + . gene_name=GENE2;",
+        ]);
+        let annotate_with = |lenient| {
+            let mut output = vec![];
+            annotate(
+                Box::new(queries.as_bytes()),
+                Box::new(targets.as_bytes()),
+                Box::new(&mut output),
+                &Options {
+                    lenient,
+                    verbosity: Verbosity::Quiet,
+                    ..Default::default()
+                },
+            )
+            .map(|_| String::from_utf8(output).unwrap())
+        };
+
+        let e = annotate_with(false).unwrap_err();
+        let e = e.downcast_ref::<ParseError>().unwrap();
+        assert_eq!((e.file, e.line, e.column), (FileKind::Bed, 2, Some(2)));
+
+        let expected = to_str(&["chr1  10  50  GENE1", "chr1  100 150 GENE2"]);
+        assert_eq!(&expected.trim(), &annotate_with(true).unwrap().trim());
+    }
+
+    #[test]
+    fn test_gff3() {
+        let queries = to_str(&["chr1  60  70", "chr1  500 600"]);
+        let targets = [
+            "chr1\tensembl\tgene\t1\t1000\t.\t+\t.\tID=gene:G1;Name=GENE1;biotype=protein_coding",
+            "chr1\tensembl\tmRNA\t1\t300\t.\t+\t.\tID=transcript:T1;Parent=gene:G1",
+            "chr1\tensembl\tCDS\t51\t100\t.\t+\t0\tParent=transcript:T1",
+            "chr1\tensembl\tgene\t401\t700\t.\t+\t.\tID=gene:G2;gene_name=GENE%2C2",
+        ]
+        .join("\n");
+        let expected = to_str(&["chr1  60  70  GENE1  CDS", "chr1  500 600 GENE,2  gene"]);
+        let mut output = vec![];
+
+        let summary = annotate(
+            Box::new(queries.as_bytes()),
+            Box::new(targets.as_bytes()),
+            Box::new(&mut output),
+            &Options {
+                format: Format::Gff3,
+                fields: parse_fields("gene_name,feature_type").unwrap(),
+                ..Default::default()
+            },
+        )
+        .expect("Cannot annotate BED file");
+
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(&expected.trim(), &output.trim());
+        // Features with an ID are also held to resolve Parent links:
+        assert_eq!(summary.peak_features, 4 + 3);
+    }
+
+    #[test]
+    fn test_gff3_refseq() {
+        let queries = to_str(&[
+            "NC_000013.11  32315700 32315800",
+            "NC_000013.11  32315550 32315560",
+        ]);
+        let targets = [
+            "NC_000013.11\tBestRefSeq\tgene\t32315508\t32400268\t.\t+\t.\tID=gene-BRCA2;Dbxref=GeneID:675;Name=BRCA2;gbkey=Gene;gene=BRCA2;gene_biotype=protein_coding",
+            "NC_000013.11\tBestRefSeq\tmRNA\t32315508\t32400268\t.\t+\t.\tID=rna-NM_000059.4;Parent=gene-BRCA2;Dbxref=GeneID:675;gbkey=mRNA;gene=BRCA2;tag=MANE Select;transcript_id=NM_000059.4",
+            "NC_000013.11\tBestRefSeq\texon\t32315508\t32315667\t.\t+\t.\tID=exon-NM_000059.4-1;Parent=rna-NM_000059.4;gbkey=mRNA;gene=BRCA2;transcript_id=NM_000059.4",
+            "NC_000013.11\tBestRefSeq\tfive_prime_UTR\t32315508\t32315667\t.\t+\t.\tParent=rna-NM_000059.4",
+            "NC_000013.11\tBestRefSeq\texon\t32316422\t32316527\t.\t+\t.\tID=exon-NM_000059.4-2;Parent=rna-NM_000059.4;gbkey=mRNA;gene=BRCA2;transcript_id=NM_000059.4",
+        ]
+        .join("\n");
+        let expected = to_str(&[
+            "NC_000013.11  32315700 32315800  BRCA2  mRNA            NM_000059.4  1",
+            "NC_000013.11  32315550 32315560  BRCA2  five_prime_UTR  NM_000059.4  .",
+        ]);
+        let mut output = vec![];
+
+        annotate(
+            Box::new(queries.as_bytes()),
+            Box::new(targets.as_bytes()),
+            Box::new(&mut output),
+            &Options {
+                format: Format::Gff3,
+                fields: parse_fields("gene_name,feature_type,transcript_id,intron_numbers")
+                    .unwrap(),
+                ..Default::default()
+            },
+        )
+        .expect("Cannot annotate BED file");
+
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(&expected.trim(), &output.trim())
+    }
+
+    #[test]
+    fn test_contig_names() {
+        let queries = to_str(&["1  10  50  a", "MT 10  50  b", "chr2 10 50 c"]);
+        let targets = to_str(&[
+            "chr1  havana gene 21   60   . + . gene_name=GENE1;",
+            "chrM  havana gene 1    100  . + . gene_name=MT-GENE;",
+            "NC_000002.12  RefSeq gene 1    100  . + . gene_name=GENE2;",
+        ]);
+        let expected = to_str(&[
+            "1  10  50  a  GENE1",
+            "MT 10  50  b  MT-GENE",
+            "chr2 10 50 c GENE2",
+        ]);
+        let mut output = vec![];
+
+        annotate(
+            Box::new(queries.as_bytes()),
+            Box::new(targets.as_bytes()),
+            Box::new(&mut output),
+            &Options {
+                aliases: ContigAliases::from_reader(
+                    "# ucsc\tassembly\trefseq\nchr2\t2\tNC_000002.12\n".as_bytes(),
+                )
+                .unwrap(),
+                ..Default::default()
+            },
+        )
+        .expect("Cannot annotate BED file");
+
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(&expected.trim(), &output.trim())
+    }
+
+    #[test]
+    fn test_summary() {
+        let queries = to_str(&["chr1  10  50", "chr1  100 150", "chrUn 10 50"]);
+        let targets = to_str(&["chr1  havana gene 21   60   . + . gene_name=GENE1;"]);
+        let annotate_with = |queries: &str, strict| {
+            let mut output = vec![];
+            let summary = annotate(
+                Box::new(queries.as_bytes()),
+                Box::new(targets.as_bytes()),
+                Box::new(&mut output),
+                &Options {
+                    strict,
+                    verbosity: Verbosity::Quiet,
+                    ..Default::default()
+                },
+            );
+            (summary, output)
+        };
+
+        let (summary, output) = annotate_with(&queries, true);
+        assert_eq!(String::from_utf8(output).unwrap().lines().count(), 3);
+        let summary = summary.unwrap();
+        assert_eq!(
+            summary,
+            Summary {
+                regions: 3,
+                annotated: 1,
+                contigs: 2,
+                missing_contigs: vec!["chrUn".to_string()],
+                peak_features: 1,
+            }
+        );
+
+        let queries = to_str(&["chrUn 10 50", "chrUn2 10 50"]);
+        assert!(annotate_with(&queries, false).0.is_ok());
+        let (summary, output) = annotate_with(&queries, true);
+        assert!(summary.is_err());
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn test_bed_headers() {
+        let queries = "track name=x\nbrowser position chr1:1-100\n#comment\nchr1\t10\t50\n\n# another\nchr1\t400\t500\n";
+        let targets = to_str(&["chr1  havana gene 21   60   . + . gene_name=GENE1;"]);
+        let mut output = vec![];
+
+        run(
+            Box::new(queries.as_bytes()),
+            Box::new(targets.as_bytes()),
+            Box::new(&mut output),
+        )
+        .expect("Cannot annotate BED file");
+
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(output, "chr1\t10\t50\tGENE1\nchr1\t400\t500\t.\n");
+        assert_eq!(validate::validate_bed(queries.as_bytes()).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_unsorted_contigs() {
+        let queries = to_str(&[
+            "chr2  5   55",
+            "chr1  100 150",
+            "chrX  100 200",
+            "chr1  10  50",
+            "chr2  60  70",
+        ]);
+        let targets = to_str(&[
+            "chr1  havana gene 21   60   . + . gene_name=GENE1;",
+            "chr1  havana gene 91   170  . + . gene_name=GENE2;",
+            "chr2  havana gene 1    500  . + . gene_name=GENE3;",
+            "chr3  havana gene 1    500  . + . gene_name=GENE4;",
+        ]);
+        let expected = to_str(&[
+            "chr2  5   55  GENE3",
+            "chr1  100 150 GENE2",
+            "chrX  100 200 .",
+            "chr1  10  50  GENE1",
+            "chr2  60  70  GENE3",
+        ]);
+        let mut output = vec![];
+
+        run(
+            Box::new(queries.as_bytes()),
+            Box::new(targets.as_bytes()),
+            Box::new(&mut output),
+        )
+        .expect("Cannot annotate BED file");
+
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(&expected.trim(), &output.trim())
+    }
+
+    #[test]
+    fn test_intermixed_unsorted_records() {
+        let starts: Vec<u64> = (0..20000).map(|i| (i * 7919) % 100000).collect();
+        let queries: Vec<String> = starts
+            .iter()
+            .enumerate()
+            .map(|(i, start)| format!("chr{}\t{start}\t{}", 1 + i % 3, start + 10))
+            .collect();
+        let targets = to_str(&[
+            "chr1  havana gene 1     50000  . + . gene_name=GENE1;",
+            "chr2  havana gene 50001 100000 . + . gene_name=GENE2;",
+        ]);
+        let annotate_with = |keep_order| {
+            let mut output = vec![];
+            let opts = Options {
+                keep_order,
+                ..Default::default()
+            };
+            annotate(
+                queries.join("\n").as_bytes(),
+                targets.as_bytes(),
+                &mut output,
+                &opts,
+            )
+            .unwrap();
+            String::from_utf8(output).unwrap()
+        };
+
+        let output = annotate_with(true);
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), queries.len());
+        for ((i, start), line) in starts.iter().enumerate().zip(&lines) {
+            let gene = match i % 3 {
+                0 if *start < 50000 => "GENE1",
+                1 if start + 10 > 50000 => "GENE2",
+                _ => ".",
+            };
+            assert_eq!(*line, format!("{}\t{gene}", queries[i]));
+        }
+
+        // Without keeping the order, records of each contig come out in coordinate order:
+        let output = annotate_with(false);
+        let chr1: Vec<u64> = output
+            .lines()
+            .filter(|x| x.starts_with("chr1\t"))
+            .map(|x| x.split('\t').nth(1).unwrap().parse().unwrap())
+            .collect();
+        assert_eq!(chr1.len(), starts.iter().step_by(3).count());
+        assert!(chr1.windows(2).all(|x| x[0] <= x[1]));
+    }
+
+    /// Features that record how much output was written when the first feature of each
+    /// contig was read.
+    struct RecordingFeatures<'a> {
+        features: std::vec::IntoIter<GffLine>,
+        output: &'a RefCell<Vec<u8>>,
+        written: &'a RefCell<Vec<(String, usize)>>,
+    }
+
+    impl Iterator for RecordingFeatures<'_> {
+        type Item = Result<GffLine, ParseError>;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            let rec = self.features.next()?;
+            let mut written = self.written.borrow_mut();
+            if written
+                .last()
+                .is_none_or(|(contig, _)| *contig != rec.contig)
+            {
+                written.push((rec.contig.clone(), self.output.borrow().len()));
+            }
+            Some(Ok(rec))
+        }
+    }
+
+    impl Features for RecordingFeatures<'_> {}
+
+    struct SharedWriter<'a>(&'a RefCell<Vec<u8>>);
+
+    impl io::Write for SharedWriter<'_> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_streamed_output() {
+        let targets = to_str(&[
+            "chr1  havana gene 1 1000 . + . gene_name=GENE1;",
+            "chr2  havana gene 1 1000 . + . gene_name=GENE2;",
+            "chr3  havana gene 1 1000 . + . gene_name=GENE3;",
+        ]);
+        let targets = gff::Reader::new(targets.as_bytes(), Format::Gtf, None)
+            .collect::<Result<Vec<GffLine>, ParseError>>()
+            .unwrap();
+        // Returns the number of bytes written when the features of each contig are read,
+        // and the whole output:
+        let annotate_with = |contigs: &[&str], intermixed, options: &Options| {
+            let queries: Vec<String> = (0..3000)
+                .map(|i| match intermixed {
+                    true => format!("{}\t{i}\t{}\n", contigs[i % 3], i + 1),
+                    false => format!("{}\t{i}\t{}\n", contigs[i / 1000], i + 1),
+                })
+                .collect();
+            let output = RefCell::new(vec![]);
+            let written = RefCell::new(vec![]);
+            let features = RecordingFeatures {
+                features: targets.clone().into_iter(),
+                output: &output,
+                written: &written,
+            };
+            let writer = SharedWriter(&output);
+            let result = annotate_features(queries.concat().as_bytes(), writer, options, |_| {
+                Ok(features)
+            });
+            let written: Vec<usize> = written.into_inner().into_iter().map(|x| x.1).collect();
+            (result.map(|_| written), output.into_inner())
+        };
+        let options = Options {
+            verbosity: Verbosity::Quiet,
+            ..Default::default()
+        };
+
+        // Lines of each contig are written once its features are read, so lines of chr1 are
+        // written by the time the features of chr3 are read:
+        let contigs = ["chr1", "chr2", "chr3"];
+        let (written, output) = annotate_with(&contigs, false, &options);
+        assert!(written.unwrap()[2] > 0);
+        assert_eq!(output.iter().filter(|&&x| x == b'\n').count(), 3000);
+        // Unless the order of the BED file is kept, and lines of later contigs are intermixed:
+        let (written, _) = annotate_with(&contigs, true, &options);
+        assert_eq!(written.unwrap(), [0, 0, 0]);
+        let sorted = Options {
+            keep_order: false,
+            ..options.clone()
+        };
+        let (written, _) = annotate_with(&contigs, true, &sorted);
+        assert!(written.unwrap()[2] > 0);
+        // Lines of later contigs wait for earlier contigs of the BED file:
+        let (written, _) = annotate_with(&["chr3", "chr1", "chr2"], false, &options);
+        assert_eq!(written.unwrap(), [0, 0, 0]);
+
+        // Output is held back as long as a strict run may fail:
+        let strict = Options {
+            strict: true,
+            ..options.clone()
+        };
+        let (written, _) = annotate_with(&contigs, false, &strict);
+        assert!(written.unwrap()[2] > 0);
+        let (result, output) = annotate_with(&["chrUn", "chrUn2", "chrUn3"], false, &strict);
+        assert!(result.is_err());
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn test_annotate_index() {
+        let queries = to_str(&["chr2  5   55", "1  100 150", "chrX  100 200"]);
+        let targets = to_str(&[
+            "chr1  havana gene 91   170  . + . gene_name=GENE2;",
+            "chr1  havana CDS  101  120  . + . gene_name=GENE1;",
+            "chr2  havana gene 1    500  . + . gene_name=GENE3;",
+            "chr3  havana gene 1    500  . + . gene_name=GENE4;",
+        ]);
+        let options = Options {
+            format: Format::Gff3,
+            verbosity: Verbosity::Quiet,
+            ..Default::default()
+        };
+        let mut expected = vec![];
+        annotate(
+            queries.as_bytes(),
+            targets.as_bytes(),
+            &mut expected,
+            &options,
+        )
+        .unwrap();
+
+        let mut index = vec![];
+        binary::write_index(targets.as_bytes(), &mut index, &options).unwrap();
+        let mut output = vec![];
+        annotate_index(
+            queries.as_bytes(),
+            io::Cursor::new(index),
+            &mut output,
+            &options,
+        )
+        .unwrap();
+        assert_eq!(output, expected);
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "chr2\t5\t55\tGENE3\n1\t100\t150\tGENE1\nchrX\t100\t200\t.\n"
+        );
+    }
+
+    #[test]
+    fn test_threads() {
+        // Contigs of the BED file, one of which is absent from the annotation:
+        let queries: Vec<String> = (0..3000)
+            .map(|i| {
+                let start = (i * 37) % 5000;
+                format!("chr{}\t{start}\t{}", 1 + i % 9, start + 20)
+            })
+            .collect();
+        let queries = queries.join("\n");
+        let targets: Vec<String> = (1..9)
+            .flat_map(|i| {
+                [
+                    format!("chr{i} havana gene 1    1000 . + . gene_name=GENE{i}A;"),
+                    format!("chr{i} havana gene 501  3000 . - . gene_name=GENE{i}B;"),
+                    format!("chr{i} havana gene 2001 4000 . + . gene_name=GENE{i}C;"),
+                ]
+            })
+            .collect();
+        let targets = to_str(&targets.iter().map(|x| x.as_str()).collect::<Vec<&str>>());
+        let annotate_with = |threads| {
+            let mut output = vec![];
+            let opts = Options {
+                mode: Mode::All,
+                nearest: Some(Nearest {
+                    direction: Direction::Any,
+                    max_distance: None,
+                }),
+                threads,
+                ..Default::default()
+            };
+            let summary = annotate(queries.as_bytes(), targets.as_bytes(), &mut output, &opts);
+            (output, summary.unwrap())
+        };
+        let (expected, expected_summary) = annotate_with(1);
+        assert_eq!(expected_summary.regions, 3000);
+        assert_eq!(expected_summary.missing_contigs, ["chr9"]);
+        for threads in [2, 4] {
+            let (output, summary) = annotate_with(threads);
+            assert_eq!(output, expected);
+            // Features of several contigs may be held at once:
+            assert!(summary.peak_features >= expected_summary.peak_features);
+            let summary = Summary {
+                peak_features: expected_summary.peak_features,
+                ..summary
+            };
+            assert_eq!(summary, expected_summary);
+        }
+    }
 }
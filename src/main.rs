@@ -1,50 +1,460 @@
-use bedanno;
+use anyhow::Context;
+use bedanno::contig::ContigAliases;
+use bedanno::dialect::Dialect;
+use bedanno::gff::Format;
+use bedanno::priority::{read_gene_list, Criterion, Priority};
+use clap::{ArgGroup, Args, Parser, Subcommand, ValueEnum};
 use flate2::read::GzDecoder;
-use std::env;
 use std::fs;
 use std::io;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 
-fn main() {
-    let mut args = env::args();
-    args.next();
+const HG38_GTF: &str = "data/hg38/gencode.v43.basic.annotation.gtf.gz";
 
-    let query: Box<dyn io::Read> = match args.next() {
+/// Assigns gene names to regions in a BED file.
+#[derive(Parser)]
+#[command(name = "bedanno", version)]
+struct Cli {
+    /// Print progress messages to stderr.
+    #[arg(short, long, global = true, conflicts_with = "quiet")]
+    verbose: bool,
+    /// Do not print warnings to stderr.
+    #[arg(short, long, global = true)]
+    quiet: bool,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Annotate BED regions with overlapping genes.
+    Annotate(Box<AnnotateArgs>),
+    /// Pre-parse a GTF/GFF file into a binary annotation file, which loads faster with --gtf.
+    Index(IndexArgs),
+    /// Check that BED and GTF/GFF files are well-formed.
+    Validate(InspectArgs),
+    /// Print the number of records and covered bases per contig.
+    Stats(InspectArgs),
+}
+
+#[derive(Args)]
+struct AnnotateArgs {
+    /// BED file to annotate, optionally gzipped. Reads stdin if omitted or `-`.
+    #[arg(short, long, default_value = "-")]
+    input: PathBuf,
+    #[command(flatten)]
+    reference: ReferenceArgs,
+    /// Output file. Writes to stdout if omitted or `-`.
+    #[arg(short, long, default_value = "-")]
+    output: PathBuf,
+    /// Comma-separated list of columns to append: GTF/GFF attributes (gene_name, gene_id, ...)
+    /// or computed values (feature_type, strand, start, end, overlap_bp, overlap_query_frac,
+    /// overlap_feature_frac, region_class, region_class_bases, exon_numbers, intron_numbers).
+    #[arg(long, default_value = "gene_name")]
+    fields: String,
+    /// Report only the top-ranked gene, or all overlapping genes.
+    #[arg(long, value_enum, default_value_t = ModeArg::Best)]
+    mode: ModeArg,
+    /// With `--mode all`, report at most this many genes per region.
+    #[arg(long)]
+    max_genes: Option<usize>,
+    /// Write records sorted by coordinate within each contig instead of in input order.
+    #[arg(long)]
+    sort: bool,
+    /// Skip malformed BED and GTF/GFF lines instead of failing, and report how many were skipped.
+    #[arg(long)]
+    lenient: bool,
+    /// Attribute naming conventions of the annotation.
+    #[arg(long, value_enum, default_value_t = DialectArg::Auto)]
+    dialect: DialectArg,
+    /// Contig alias table in UCSC chromAlias format, to match contigs named differently
+    /// in the BED and annotation files (the `chr` prefix is reconciled automatically).
+    #[arg(long)]
+    contig_aliases: Option<PathBuf>,
+    /// Fail if none of the BED contigs is found in the annotation.
+    #[arg(long)]
+    strict: bool,
+    /// Ranking criteria separated by `;`, from the most important one, e.g.
+    /// "genes BRCA1,BRCA2; mane; tag Ensembl_canonical; overlap".
+    #[arg(long, conflicts_with = "priority_file")]
+    priority: Option<String>,
+    /// TOML file with ranking criteria, as an array of `[[criteria]]` tables with a `name` and
+    /// optional `values`.
+    #[arg(long)]
+    priority_file: Option<PathBuf>,
+    /// File with gene names or Ensembl gene IDs, one per line, ranked ahead of every other
+    /// criterion.
+    #[arg(long)]
+    prefer_genes: Option<PathBuf>,
+    /// Report only genes listed in the --prefer-genes file, and `.` for regions without any.
+    #[arg(long, requires = "prefer_genes")]
+    only_preferred: bool,
+    /// Only report features carrying this tag, e.g. MANE_Select, Ensembl_canonical or basic.
+    /// Can be given several times.
+    #[arg(long, value_name = "TAG")]
+    require_tag: Vec<String>,
+    /// For regions overlapping no gene, append the closest gene and the signed distance to it
+    /// (negative upstream of the gene). Optionally only genes the region is upstream or
+    /// downstream of.
+    #[arg(long, value_enum, num_args = 0..=1, default_missing_value = "any")]
+    nearest: Option<DirectionArg>,
+    /// With --nearest, do not report genes further away than this many bases.
+    #[arg(long, requires = "nearest")]
+    max_distance: Option<u64>,
+    /// Annotate regions within this many bases upstream of a gene or transcript start as the
+    /// `promoter` of that gene.
+    #[arg(long)]
+    promoter: Option<u64>,
+    /// Extend genes upstream by this many bases before looking for overlaps.
+    #[arg(long, default_value_t = 0)]
+    upstream_flank: u64,
+    /// Extend genes downstream by this many bases before looking for overlaps.
+    #[arg(long, default_value_t = 0)]
+    downstream_flank: u64,
+    /// Only report genes on the same strand as the BED record (column 6).
+    #[arg(short = 's', long, conflicts_with = "opposite_strand")]
+    same_strand: bool,
+    /// Only report genes on the opposite strand of the BED record (column 6).
+    #[arg(short = 'S', long)]
+    opposite_strand: bool,
+    /// Number of threads annotating contigs, or 0 to use all CPUs.
+    #[arg(short, long, default_value_t = 1)]
+    threads: usize,
+}
+
+#[derive(Args)]
+struct IndexArgs {
+    /// GTF/GFF annotation file, optionally gzipped.
+    gtf: PathBuf,
+    /// Binary annotation file to write, conventionally with a `.bidx` extension.
+    #[arg(short, long)]
+    output: PathBuf,
+    /// Skip malformed GTF/GFF lines instead of failing, and report how many were skipped.
+    #[arg(long)]
+    lenient: bool,
+    /// Attribute naming conventions of the annotation.
+    #[arg(long, value_enum, default_value_t = DialectArg::Auto)]
+    dialect: DialectArg,
+}
+
+#[derive(Args)]
+struct ReferenceArgs {
+    /// GTF/GFF annotation file, optionally gzipped, or binary annotation file written by
+    /// `bedanno index`.
+    #[arg(short, long, conflicts_with = "genome")]
+    gtf: Option<PathBuf>,
+    /// Built-in annotation to use when --gtf is not given.
+    #[arg(long, default_value = "hg38")]
+    genome: String,
+}
+
+#[derive(Args)]
+#[command(group(ArgGroup::new("files").required(true).multiple(true).args(["input", "gtf"])))]
+struct InspectArgs {
+    /// BED file, optionally gzipped.
+    #[arg(short, long)]
+    input: Option<PathBuf>,
+    /// GTF/GFF annotation file, optionally gzipped.
+    #[arg(short, long)]
+    gtf: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ModeArg {
+    Best,
+    All,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum DirectionArg {
+    Any,
+    Upstream,
+    Downstream,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum DialectArg {
+    /// Detect from the attributes of each feature.
+    Auto,
+    Gencode,
+    Ensembl,
+    Refseq,
+}
+
+impl ReferenceArgs {
+    fn path(&self) -> anyhow::Result<PathBuf> {
+        if let Some(gtf) = &self.gtf {
+            return Ok(gtf.clone());
+        }
+        match self.genome.as_str() {
+            "hg38" => Ok(PathBuf::from(HG38_GTF)),
+            genome => Err(anyhow::anyhow!(
+                "Unknown genome {genome}, only hg38 is built in; use --gtf to provide an annotation"
+            )),
+        }
+    }
+}
+
+fn open_input(path: &Path) -> anyhow::Result<Box<dyn io::Read>> {
+    if path == Path::new("-") {
+        return Ok(Box::new(io::stdin()));
+    }
+    let reader = fs::File::open(path).with_context(|| format!("Cannot open {}", path.display()))?;
+    if path.extension().is_some_and(|x| x == "gz") {
+        Ok(Box::new(GzDecoder::new(reader)))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+fn open_output(path: &Path) -> anyhow::Result<Box<dyn io::Write>> {
+    if path == Path::new("-") {
+        return Ok(Box::new(io::stdout()));
+    }
+    Ok(Box::new(LazyFile {
+        path: path.to_path_buf(),
+        file: None,
+    }))
+}
+
+/// Output file created on the first write, so that runs failing before writing anything
+/// leave no empty file behind.
+struct LazyFile {
+    path: PathBuf,
+    file: Option<fs::File>,
+}
+
+impl LazyFile {
+    fn file(&mut self) -> io::Result<&mut fs::File> {
+        if self.file.is_none() {
+            let file = fs::File::create(&self.path).map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!("Cannot create {}: {e}", self.path.display()),
+                )
+            })?;
+            self.file = Some(file);
+        }
+        Ok(self.file.as_mut().expect("file is created"))
+    }
+}
+
+impl io::Write for LazyFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file()?.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file()?.flush()
+    }
+}
+
+fn annotation_format(path: &Path) -> anyhow::Result<Format> {
+    Format::from_path(path)
+        .ok_or_else(|| anyhow::anyhow!("Reference {} must be a GFF or GTF file", path.display()))
+}
+
+fn is_index(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|x| x == bedanno::binary::EXTENSION)
+}
+
+impl DialectArg {
+    fn dialect(self) -> Option<Dialect> {
+        match self {
+            DialectArg::Auto => None,
+            DialectArg::Gencode => Some(Dialect::Gencode),
+            DialectArg::Ensembl => Some(Dialect::Ensembl),
+            DialectArg::Refseq => Some(Dialect::RefSeq),
+        }
+    }
+}
+
+fn annotate(args: &AnnotateArgs, verbosity: bedanno::Verbosity) -> anyhow::Result<()> {
+    let gff_path = args.reference.path()?;
+    let format = if is_index(&gff_path) {
+        if args.dialect != DialectArg::Auto {
+            return Err(anyhow::anyhow!(
+                "--dialect cannot be used with the index file {}, pass it to bedanno index instead",
+                gff_path.display()
+            ));
+        }
+        Format::Gtf
+    } else {
+        annotation_format(&gff_path)?
+    };
+
+    let mut priority = match (&args.priority, &args.priority_file) {
+        (Some(spec), _) => spec.parse().context("Cannot parse --priority")?,
+        (None, Some(path)) => {
+            let text = fs::read_to_string(path)
+                .with_context(|| format!("Cannot read {}", path.display()))?;
+            Priority::from_toml(&text)
+                .with_context(|| format!("Cannot parse priority file {}", path.display()))?
+        }
+        (None, None) => Priority::default(),
+    };
+    let preferred_genes = match &args.prefer_genes {
         Some(path) => {
-            let reader = fs::File::open(&path).unwrap();
-            if path.ends_with(".gz") {
-                Box::new(GzDecoder::new(reader))
-            } else {
-                Box::new(reader)
-            }
-        }
-        None => Box::new(io::stdin()),
+            let genes = read_gene_list(open_input(path)?)
+                .with_context(|| format!("Cannot read gene list {}", path.display()))?;
+            priority.criteria.insert(0, Criterion::Genes(genes.clone()));
+            Some(genes)
+        }
+        None => None,
     };
 
-    let mut gff_path: String = args.next().unwrap_or("hg38".to_string());
-    if gff_path == "hg38" {
-        gff_path = "data/hg38/gencode.v43.basic.annotation.gtf.gz".to_string();
-    }
-    let _gff_type = {
-        let p = gff_path.trim_end_matches(".gz").to_owned();
-        if p.ends_with(".gff") || p.ends_with(".gff3") {
-            "gff3"
-        } else if p.ends_with(".gff2") {
-            "gff2"
-        } else if p.ends_with(".gtf") {
-            "gtf"
+    let options = bedanno::Options {
+        keep_order: !args.sort,
+        mode: match args.mode {
+            ModeArg::Best => bedanno::Mode::Best,
+            ModeArg::All => bedanno::Mode::All,
+        },
+        max_genes: args.max_genes,
+        fields: bedanno::parse_fields(&args.fields).context("Cannot parse --fields")?,
+        verbosity,
+        lenient: args.lenient,
+        format,
+        dialect: args.dialect.dialect(),
+        aliases: match &args.contig_aliases {
+            Some(path) => ContigAliases::from_reader(open_input(path)?)
+                .with_context(|| format!("Cannot read contig aliases {}", path.display()))?,
+            None => ContigAliases::default(),
+        },
+        strict: args.strict,
+        priority,
+        only_genes: preferred_genes.filter(|_| args.only_preferred),
+        required_tags: args.require_tag.clone(),
+        nearest: args.nearest.map(|direction| bedanno::Nearest {
+            direction: match direction {
+                DirectionArg::Any => bedanno::Direction::Any,
+                DirectionArg::Upstream => bedanno::Direction::Upstream,
+                DirectionArg::Downstream => bedanno::Direction::Downstream,
+            },
+            max_distance: args.max_distance,
+        }),
+        promoter: args.promoter,
+        upstream_flank: args.upstream_flank,
+        downstream_flank: args.downstream_flank,
+        strandedness: if args.same_strand {
+            bedanno::Strandedness::Same
+        } else if args.opposite_strand {
+            bedanno::Strandedness::Opposite
         } else {
-            panic!("Reference must be a GFF or GTF file, or genome name (hg38 is supported)")
-        }
+            bedanno::Strandedness::Any
+        },
+        threads: match args.threads {
+            0 => std::thread::available_parallelism().map_or(1, |x| x.get()),
+            n => n,
+        },
     };
 
-    let reader = fs::File::open(&gff_path).expect("Cannot open GTF/GFF file");
-    let target: Box<dyn io::Read> = if gff_path.ends_with(".gz") {
-        Box::new(GzDecoder::new(reader))
+    let query = open_input(&args.input)?;
+    let output = open_output(&args.output)?;
+    if is_index(&gff_path) {
+        let index = fs::File::open(&gff_path)
+            .with_context(|| format!("Cannot open {}", gff_path.display()))?;
+        bedanno::annotate_index(query, index, output, &options)
+            .with_context(|| format!("Cannot annotate with {}", gff_path.display()))?;
+    } else if let Some(index_path) = bedanno::tabix::find_index(&gff_path) {
+        if verbosity >= bedanno::Verbosity::Verbose {
+            eprintln!("Using index {}", index_path.display());
+        }
+        let index = bedanno::tabix::Index::from_reader(open_input(&index_path)?)
+            .with_context(|| format!("Cannot read index {}", index_path.display()))?;
+        let target = fs::File::open(&gff_path)
+            .with_context(|| format!("Cannot open {}", gff_path.display()))?;
+        bedanno::annotate_tabix(query, target, &index, output, &options)
+            .with_context(|| format!("Cannot annotate with {}", gff_path.display()))?;
     } else {
-        Box::new(reader)
+        let target = open_input(&gff_path)?;
+        bedanno::annotate(query, target, output, &options)?;
+    }
+    Ok(())
+}
+
+/// Peak resident memory of the process, where the OS reports it.
+fn peak_memory() -> Option<String> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|x| x.starts_with("VmHWM:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(format!("{:.1} MB", kb as f64 / 1024.0))
+}
+
+fn index(args: &IndexArgs, verbosity: bedanno::Verbosity) -> anyhow::Result<()> {
+    let options = bedanno::Options {
+        verbosity,
+        lenient: args.lenient,
+        format: annotation_format(&args.gtf)?,
+        dialect: args.dialect.dialect(),
+        ..Default::default()
     };
+    let count =
+        bedanno::binary::write_index(open_input(&args.gtf)?, open_output(&args.output)?, &options)
+            .with_context(|| format!("Cannot index {}", args.gtf.display()))?;
+    if verbosity >= bedanno::Verbosity::Verbose {
+        eprintln!("Wrote {count} features to {}", args.output.display());
+    }
+    Ok(())
+}
+
+fn validate(args: &InspectArgs) -> anyhow::Result<()> {
+    if let Some(path) = &args.input {
+        let count = bedanno::validate::validate_bed(open_input(path)?)
+            .with_context(|| format!("Invalid BED file {}", path.display()))?;
+        println!("{}: {count} BED records OK", path.display());
+    }
+    if let Some(path) = &args.gtf {
+        let format = annotation_format(path)?;
+        let count = bedanno::validate::validate_annotation(open_input(path)?, format)
+            .with_context(|| format!("Invalid annotation file {}", path.display()))?;
+        println!("{}: {count} annotation records OK", path.display());
+    }
+    Ok(())
+}
+
+fn stats(args: &InspectArgs) -> anyhow::Result<()> {
+    if let Some(path) = &args.input {
+        let stats = bedanno::stats::bed_stats(open_input(path)?)
+            .with_context(|| format!("Cannot read BED file {}", path.display()))?;
+        bedanno::stats::write_stats(&stats, io::stdout())?;
+    }
+    if let Some(path) = &args.gtf {
+        let format = annotation_format(path)?;
+        let stats = bedanno::stats::annotation_stats(open_input(path)?, format)
+            .with_context(|| format!("Cannot read annotation file {}", path.display()))?;
+        bedanno::stats::write_stats(&stats, io::stdout())?;
+    }
+    Ok(())
+}
 
-    let output = io::stdout();
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let verbosity = if cli.quiet {
+        bedanno::Verbosity::Quiet
+    } else if cli.verbose {
+        bedanno::Verbosity::Verbose
+    } else {
+        bedanno::Verbosity::Normal
+    };
 
-    bedanno::run(query, target, output).expect("Should be able to annotate BED file")
-}
\ No newline at end of file
+    let result = match &cli.command {
+        Command::Annotate(args) => annotate(args, verbosity),
+        Command::Index(args) => index(args, verbosity),
+        Command::Validate(args) => validate(args),
+        Command::Stats(args) => stats(args),
+    };
+    if verbosity >= bedanno::Verbosity::Verbose {
+        if let Some(peak) = peak_memory() {
+            eprintln!("Peak memory: {peak}");
+        }
+    }
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("bedanno: error: {e:#}");
+            ExitCode::FAILURE
+        }
+    }
+}
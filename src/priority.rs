@@ -0,0 +1,335 @@
+use crate::dialect::canonical_feature_type;
+use crate::Annotation;
+use std::collections::HashSet;
+use std::io::{self, BufRead};
+use std::str::FromStr;
+
+/// One criterion used to rank annotations overlapping a region.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Criterion {
+    /// Feature type (column 3) in the given order. Unlisted types rank like their GENCODE
+    /// equivalent, e.g. `mRNA` like `transcript` and `five_prime_UTR` like `UTR`, or last.
+    FeatureType(Vec<String>),
+    /// Value of an attribute in the given order, e.g. `gene_type` or `level`. Unlisted values
+    /// rank last, and so do missing ones unless `NA` is listed.
+    Attribute(String, Vec<String>),
+    /// Features carrying the tag first, e.g. `MANE_Select`, `Ensembl_canonical` or `basic`.
+    Tag(String),
+    /// APPRIS tags: `appris_principal_1` to `appris_principal_5`, then `appris_alternative_1`
+    /// and `appris_alternative_2`.
+    Appris,
+    /// Features of the listed genes first, matched by name or ID.
+    Genes(HashSet<String>),
+    /// Longest overlap with the region first, then the shortest feature.
+    Overlap,
+}
+
+impl Criterion {
+    /// Appends the rank of an annotation; lower ranks win.
+    fn rank(&self, anno: &Annotation, ranks: &mut Vec<usize>) {
+        let position = |values: &[String], value: &str| {
+            values
+                .iter()
+                .position(|x| x == value)
+                .unwrap_or(values.len())
+        };
+        match self {
+            Criterion::FeatureType(types) => {
+                let rank = match position(types, anno.feature_type) {
+                    x if x == types.len() => {
+                        position(types, canonical_feature_type(anno.feature_type))
+                    }
+                    x => x,
+                };
+                ranks.push(rank);
+            }
+            Criterion::Attribute(key, values) => {
+                // The best of multiple values counts:
+                let rank = anno
+                    .attribute_values(key)
+                    .map(|x| position(values, x))
+                    .min()
+                    .unwrap_or_else(|| position(values, "NA"));
+                ranks.push(rank);
+            }
+            Criterion::Tag(tag) => ranks.push(!anno.has_tag(tag) as usize),
+            Criterion::Appris => {
+                let tags = [
+                    "appris_principal_1",
+                    "appris_principal_2",
+                    "appris_principal_3",
+                    "appris_principal_4",
+                    "appris_principal_5",
+                    "appris_alternative_1",
+                    "appris_alternative_2",
+                ];
+                ranks.push(
+                    tags.iter()
+                        .position(|x| anno.has_tag(x))
+                        .unwrap_or(tags.len()),
+                );
+            }
+            Criterion::Genes(genes) => ranks.push(!anno.in_genes(genes) as usize),
+            Criterion::Overlap => {
+                ranks.push(usize::MAX - anno.overlap.bases as usize);
+                ranks.push((anno.interval.end - anno.interval.start) as usize);
+            }
+        }
+    }
+}
+
+impl FromStr for Criterion {
+    type Err = anyhow::Error;
+
+    /// Parses a criterion name, optionally followed by whitespace and comma-separated values,
+    /// e.g. `mane`, `tag Ensembl_canonical` or `gene_type protein_coding,lncRNA`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, values) = s
+            .trim()
+            .split_once(char::is_whitespace)
+            .unwrap_or((s.trim(), ""));
+        let values = values
+            .split(',')
+            .map(|x| x.trim().to_string())
+            .filter(|x| !x.is_empty())
+            .collect::<Vec<String>>();
+        Criterion::new(name, values)
+    }
+}
+
+impl Criterion {
+    /// Creates a criterion from its name and values, which may be empty for criteria with
+    /// default values or without any.
+    pub fn new(name: &str, values: Vec<String>) -> anyhow::Result<Self> {
+        let or_default = |default: &[&str]| {
+            if values.is_empty() {
+                default.iter().map(|x| x.to_string()).collect()
+            } else {
+                values.clone()
+            }
+        };
+        let required = || {
+            if values.is_empty() {
+                Err(anyhow::anyhow!("Priority criterion {name:?} needs a value"))
+            } else {
+                Ok(values.clone())
+            }
+        };
+        Ok(match name {
+            "feature_type" => Criterion::FeatureType(or_default(&[
+                "CDS",
+                "stop_codon",
+                "start_codon",
+                "UTR",
+                "exon",
+                "transcript",
+                "gene",
+            ])),
+            "mane" => Criterion::Tag("MANE_Select".to_string()),
+            "tsl" => Criterion::Attribute(
+                "transcript_support_level".to_string(),
+                or_default(&["1", "2", "3", "4", "5", "NA"]),
+            ),
+            "level" => Criterion::Attribute("level".to_string(), or_default(&["1", "2", "3"])),
+            "coding" => Criterion::Attribute(
+                "transcript_type".to_string(),
+                vec!["protein_coding".to_string()],
+            ),
+            "gene_type" | "transcript_type" => {
+                Criterion::Attribute(name.to_string(), or_default(&["protein_coding"]))
+            }
+            "tag" => match required()?.as_slice() {
+                [tag] => Criterion::Tag(tag.clone()),
+                _ => return Err(anyhow::anyhow!("Priority criterion \"tag\" takes one tag")),
+            },
+            "appris" => Criterion::Appris,
+            "genes" => Criterion::Genes(required()?.into_iter().collect()),
+            "overlap" => Criterion::Overlap,
+            "" => return Err(anyhow::anyhow!("Empty priority criterion")),
+            key => Criterion::Attribute(key.to_string(), required()?),
+        })
+    }
+}
+
+/// Ordered criteria that rank the annotations overlapping a region, from the most to the
+/// least important one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Priority {
+    pub criteria: Vec<Criterion>,
+}
+
+impl Priority {
+    /// Returns the sort key of an annotation; lower keys have higher priority.
+    pub(crate) fn key(&self, anno: &Annotation) -> Vec<usize> {
+        let mut ranks = vec![];
+        for criterion in &self.criteria {
+            criterion.rank(anno, &mut ranks);
+        }
+        ranks
+    }
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        "feature_type; mane; tsl; level; coding; overlap"
+            .parse()
+            .unwrap()
+    }
+}
+
+impl FromStr for Priority {
+    type Err = anyhow::Error;
+
+    /// Parses criteria separated by `;`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let criteria = s
+            .split(';')
+            .filter(|x| !x.trim().is_empty())
+            .map(Criterion::from_str)
+            .collect::<anyhow::Result<Vec<Criterion>>>()?;
+        Ok(Self { criteria })
+    }
+}
+
+impl Priority {
+    /// Parses a TOML priority file: an array of `criteria` tables, from the most to the least
+    /// important one, each with a `name` and optional `values`.
+    ///
+    /// ```toml
+    /// [[criteria]]
+    /// name = "genes"
+    /// values = ["BRCA1", "BRCA2"]
+    ///
+    /// [[criteria]]
+    /// name = "mane"
+    /// ```
+    pub fn from_toml(s: &str) -> anyhow::Result<Self> {
+        let mut table: toml::Table = s.parse()?;
+        if let Some(key) = table.keys().find(|x| *x != "criteria") {
+            return Err(anyhow::anyhow!("Unknown priority file key {key:?}"));
+        }
+        let Some(toml::Value::Array(criteria)) = table.remove("criteria") else {
+            return Err(anyhow::anyhow!(
+                "Priority file needs a [[criteria]] array of tables"
+            ));
+        };
+        let criteria = criteria
+            .into_iter()
+            .enumerate()
+            .map(|(i, x)| {
+                let criterion = || format!("Priority criterion {}", i + 1);
+                let toml::Value::Table(mut x) = x else {
+                    return Err(anyhow::anyhow!("{} must be a table", criterion()));
+                };
+                let Some(toml::Value::String(name)) = x.remove("name") else {
+                    return Err(anyhow::anyhow!("{} needs a string name", criterion()));
+                };
+                let values = match x.remove("values") {
+                    None => vec![],
+                    Some(toml::Value::Array(values)) => values
+                        .into_iter()
+                        .map(|x| match x {
+                            toml::Value::String(x) => Ok(x),
+                            // Allowing unquoted TSLs and levels, e.g. `values = [1, 2]`:
+                            toml::Value::Integer(x) => Ok(x.to_string()),
+                            _ => Err(anyhow::anyhow!(
+                                "Values of {name:?} must be strings or integers"
+                            )),
+                        })
+                        .collect::<anyhow::Result<Vec<String>>>()?,
+                    Some(_) => return Err(anyhow::anyhow!("Values of {name:?} must be an array")),
+                };
+                if let Some(key) = x.keys().next() {
+                    return Err(anyhow::anyhow!("Unknown key {key:?} of {name:?}"));
+                }
+                Criterion::new(&name, values)
+            })
+            .collect::<anyhow::Result<Vec<Criterion>>>()?;
+        Ok(Self { criteria })
+    }
+}
+
+/// Reads a list of gene names or IDs, one per line. Lines starting with `#` are ignored, and
+/// so are further columns.
+pub fn read_gene_list(reader: impl io::Read) -> anyhow::Result<HashSet<String>> {
+    let mut genes = HashSet::new();
+    for line in io::BufReader::new(reader).lines() {
+        let line = line?;
+        if line.starts_with('#') {
+            continue;
+        }
+        if let Some(gene) = line.split_whitespace().next() {
+            genes.insert(gene.to_string());
+        }
+    }
+    Ok(genes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_priority() {
+        let priority: Priority =
+            "genes BRCA1, BRCA2; tag Ensembl_canonical; gene_type protein_coding,lncRNA; appris"
+                .parse()
+                .unwrap();
+        assert_eq!(
+            priority.criteria,
+            vec![
+                Criterion::Genes(HashSet::from(["BRCA1".to_string(), "BRCA2".to_string()])),
+                Criterion::Tag("Ensembl_canonical".to_string()),
+                Criterion::Attribute(
+                    "gene_type".to_string(),
+                    vec!["protein_coding".to_string(), "lncRNA".to_string()]
+                ),
+                Criterion::Appris,
+            ]
+        );
+        assert_eq!(Priority::default().criteria.len(), 6);
+        assert!("tag".parse::<Priority>().is_err());
+        assert!("unknown_attribute".parse::<Priority>().is_err());
+    }
+
+    #[test]
+    fn test_priority_file() {
+        let priority = Priority::from_toml(
+            r#"
+            # Panel genes first:
+            [[criteria]]
+            name = "genes"
+            values = ["BRCA1", "BRCA2"]
+
+            [[criteria]]
+            name = "tsl"
+            values = [1, 2, "NA"]
+
+            [[criteria]]
+            name = "tag"
+            values = ["Ensembl_canonical"]
+
+            [[criteria]]
+            name = "overlap"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            priority.criteria,
+            vec![
+                Criterion::Genes(HashSet::from(["BRCA1".to_string(), "BRCA2".to_string()])),
+                Criterion::Attribute(
+                    "transcript_support_level".to_string(),
+                    vec!["1".to_string(), "2".to_string(), "NA".to_string()]
+                ),
+                Criterion::Tag("Ensembl_canonical".to_string()),
+                Criterion::Overlap,
+            ]
+        );
+        assert!(Priority::from_toml("[[criteria]]\nname = \"tag\"").is_err());
+        assert!(Priority::from_toml("[[criteria]]\nvalue = [\"basic\"]").is_err());
+        assert!(Priority::from_toml("[[criteria]]\nname = \"mane\"\nvalue = 1").is_err());
+        assert!(Priority::from_toml("genes = [\"BRCA1\"]").is_err());
+        assert!(Priority::from_toml("genes BRCA1").is_err());
+    }
+}
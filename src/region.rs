@@ -0,0 +1,293 @@
+use crate::gff::GffLine;
+use crate::index::IntervalTree;
+use crate::Interval;
+use std::collections::HashMap;
+use std::fmt;
+
+/// Promoter length used for region classes when `Options::promoter` is not set.
+pub(crate) const DEFAULT_PROMOTER: u64 = 1000;
+
+/// Number of intronic bases next to an exon that are classified as splice region.
+pub(crate) const SPLICE_REGION: u64 = 8;
+
+/// Part of a transcript model a base falls in, from the most to the least severe.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub(crate) enum RegionClass {
+    Cds,
+    SpliceRegion,
+    Utr5,
+    Utr3,
+    NoncodingExon,
+    Intron,
+    Promoter,
+    Intergenic,
+}
+
+impl fmt::Display for RegionClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RegionClass::Cds => write!(f, "CDS"),
+            RegionClass::SpliceRegion => write!(f, "splice_region"),
+            RegionClass::Utr5 => write!(f, "5UTR"),
+            RegionClass::Utr3 => write!(f, "3UTR"),
+            RegionClass::NoncodingExon => write!(f, "noncoding_exon"),
+            RegionClass::Intron => write!(f, "intron"),
+            RegionClass::Promoter => write!(f, "promoter"),
+            RegionClass::Intergenic => write!(f, "intergenic"),
+        }
+    }
+}
+
+/// Exons and coding part of a transcript, assembled from its features.
+#[derive(Default)]
+struct Transcript {
+    span: Option<Interval>,
+    strand: char,
+    exons: Vec<Interval>,
+    /// From the first to the last coding base, including start and stop codons.
+    cds: Option<Interval>,
+}
+
+impl Transcript {
+    /// Splits the transcript and its promoter into consecutive classified segments.
+    fn segments(&self, promoter: u64) -> Vec<(Interval, RegionClass)> {
+        let mut exons = self.exons.clone();
+        if exons.is_empty() {
+            exons.extend(self.span.clone());
+        }
+        exons.sort();
+        let minus = self.strand == '-';
+        let mut segments = vec![];
+        for (i, exon) in exons.iter().enumerate() {
+            if i > 0 {
+                // Intron between the previous exon and this one:
+                let (st, en) = (exons[i - 1].end, exon.start);
+                if st < en {
+                    let splice = SPLICE_REGION.min((en - st) / 2);
+                    segments.push((Interval::new(st, st + splice), RegionClass::SpliceRegion));
+                    segments.push((Interval::new(st + splice, en - splice), RegionClass::Intron));
+                    segments.push((Interval::new(en - splice, en), RegionClass::SpliceRegion));
+                }
+            }
+            let Some(cds) = &self.cds else {
+                segments.push((exon.clone(), RegionClass::NoncodingExon));
+                continue;
+            };
+            let (before, after) = if minus {
+                (RegionClass::Utr3, RegionClass::Utr5)
+            } else {
+                (RegionClass::Utr5, RegionClass::Utr3)
+            };
+            let cds_start = cds.start.clamp(exon.start, exon.end);
+            let cds_end = cds.end.clamp(cds_start, exon.end);
+            segments.push((Interval::new(exon.start, cds_start), before));
+            segments.push((Interval::new(cds_start, cds_end), RegionClass::Cds));
+            segments.push((Interval::new(cds_end, exon.end), after));
+        }
+        if let (Some(first), Some(last)) = (exons.first(), exons.last()) {
+            let window = if minus {
+                Interval::new(last.end, last.end + promoter)
+            } else {
+                Interval::new(first.start.saturating_sub(promoter), first.start)
+            };
+            segments.push((window, RegionClass::Promoter));
+        }
+        segments.retain(|(x, _)| x.start < x.end);
+        segments
+    }
+}
+
+/// Classified segments and exons of the transcripts of one contig.
+pub(crate) struct TranscriptModels {
+    /// Segments of each transcript, keyed by the extent of the transcript and its promoter.
+    transcripts: IntervalTree<(char, Vec<(Interval, RegionClass)>)>,
+    /// Strand and sorted exons of each transcript by ID.
+    exons: HashMap<String, (char, Vec<Interval>)>,
+}
+
+impl TranscriptModels {
+    /// Assembles transcripts from exons, CDS, start and stop codons and transcript features,
+    /// grouped by `transcript_id` (or by `ID` and `Parent` in GFF3 files without it).
+    pub(crate) fn new(features: &[(Interval, GffLine)], promoter: u64) -> Self {
+        let mut transcripts: HashMap<&str, Transcript> = HashMap::new();
+        for (_, rec) in features {
+            let ids: Vec<&str> = match rec.attribute("transcript_id") {
+                Some(id) => vec![id],
+                None if rec.is_transcript() => rec.attribute_values("ID").collect(),
+                // GFF3 exons can be shared by several transcripts:
+                None => rec.attribute_values("Parent").collect(),
+            };
+            for id in ids {
+                let interval = &rec.interval;
+                let transcript = transcripts.entry(id).or_default();
+                transcript.strand = rec.strand;
+                match rec.feature_type.as_str() {
+                    "exon" => transcript.exons.push(interval.clone()),
+                    "CDS" | "start_codon" | "stop_codon" => {
+                        let cds = transcript.cds.get_or_insert(interval.clone());
+                        *cds =
+                            Interval::new(cds.start.min(interval.start), cds.end.max(interval.end));
+                    }
+                    _ if rec.is_transcript() => transcript.span = Some(interval.clone()),
+                    _ => {}
+                }
+            }
+        }
+        let mut exons = HashMap::new();
+        let transcripts = transcripts
+            .into_iter()
+            .filter_map(|(id, mut x)| {
+                let segments = x.segments(promoter);
+                x.exons.sort();
+                exons.insert(id.to_string(), (x.strand, x.exons));
+                let start = segments.iter().map(|(x, _)| x.start).min()?;
+                let end = segments.iter().map(|(x, _)| x.end).max()?;
+                Some((Interval::new(start, end), (x.strand, segments)))
+            })
+            .collect();
+        Self {
+            transcripts: IntervalTree::new(transcripts),
+            exons,
+        }
+    }
+
+    /// Returns the numbers of the exons and of the introns of a transcript overlapped by
+    /// `query`, counted from the 5' end of the transcript and written as ranges, e.g. `10-12`.
+    pub(crate) fn exon_intron_numbers(
+        &self,
+        transcript_id: &str,
+        query: &Interval,
+    ) -> (Option<String>, Option<String>) {
+        let Some((strand, exons)) = self.exons.get(transcript_id) else {
+            return (None, None);
+        };
+        let query = Interval::new(query.start, query.end.max(query.start + 1));
+        let introns = exons
+            .windows(2)
+            .map(|x| Interval::new(x[0].end, x[1].start))
+            .collect::<Vec<Interval>>();
+        let numbers = |intervals: &[Interval]| {
+            let overlapping = intervals
+                .iter()
+                .enumerate()
+                .filter(|(_, x)| query.overlap_len(x) > 0)
+                .map(|(i, _)| match strand {
+                    '-' => intervals.len() - i,
+                    _ => i + 1,
+                });
+            let (min, max) = overlapping.fold(None, |acc, i| match acc {
+                None => Some((i, i)),
+                Some((min, max)) => Some((i.min(min), i.max(max))),
+            })?;
+            Some(if min == max {
+                min.to_string()
+            } else {
+                format!("{min}-{max}")
+            })
+        };
+        (numbers(exons), numbers(&introns))
+    }
+
+    /// Returns the number of bases of `query` in each class, assigning every base the most
+    /// severe class among the transcripts with an allowed strand. An empty query is treated as
+    /// the single base at its start.
+    pub(crate) fn classify(
+        &self,
+        query: &Interval,
+        strand: impl Fn(char) -> bool,
+    ) -> Vec<(RegionClass, u64)> {
+        let (st, en) = (query.start, query.end.max(query.start + 1));
+        let segments: Vec<&(Interval, RegionClass)> = self
+            .transcripts
+            .overlapping(query)
+            .into_iter()
+            .filter(|((x, _), _)| strand(*x))
+            .flat_map(|((_, segments), _)| segments)
+            .filter(|(x, _)| x.start < en && st < x.end)
+            .collect();
+        let mut breakpoints = vec![st, en];
+        for (x, _) in &segments {
+            breakpoints.push(x.start.clamp(st, en));
+            breakpoints.push(x.end.clamp(st, en));
+        }
+        breakpoints.sort();
+        breakpoints.dedup();
+        let mut bases: HashMap<RegionClass, u64> = HashMap::new();
+        for piece in breakpoints.windows(2) {
+            let class = segments
+                .iter()
+                .filter(|(x, _)| x.start <= piece[0] && piece[1] <= x.end)
+                .map(|(_, class)| *class)
+                .min()
+                .unwrap_or(RegionClass::Intergenic);
+            *bases.entry(class).or_default() += piece[1] - piece[0];
+        }
+        let mut bases = bases.into_iter().collect::<Vec<(RegionClass, u64)>>();
+        bases.sort();
+        bases
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gff::Format;
+
+    #[test]
+    fn test_classify() {
+        // Minus-strand transcript with exons 1001-1100, 1201-1300 and 1401-1500, coding from
+        // 1251 to 1450:
+        let features = [
+            "transcript\t1001\t1500",
+            "exon\t1001\t1100",
+            "exon\t1201\t1300",
+            "exon\t1401\t1500",
+            "CDS\t1251\t1300",
+            "CDS\t1401\t1450",
+        ]
+        .iter()
+        .map(|x| {
+            let line = format!("1\th\t{x}\t.\t-\t.\ttranscript_id \"T1\";");
+            let rec = GffLine::from_line(&line, 0, Format::Gtf, None).unwrap();
+            (rec.interval.clone(), rec)
+        })
+        .collect::<Vec<(Interval, GffLine)>>();
+        let models = TranscriptModels::new(&features, 100);
+        let classify = |start, end| {
+            models
+                .classify(&Interval::new(start, end), |_| true)
+                .iter()
+                .map(|(class, bases)| format!("{class}:{bases}"))
+                .collect::<Vec<String>>()
+                .join(",")
+        };
+        assert_eq!(classify(1460, 1470), "5UTR:10");
+        assert_eq!(classify(1040, 1050), "3UTR:10");
+        assert_eq!(classify(1240, 1260), "CDS:10,3UTR:10");
+        assert_eq!(classify(1095, 1150), "splice_region:8,3UTR:5,intron:42");
+        assert_eq!(classify(1550, 1560), "promoter:10");
+        assert_eq!(classify(1590, 1610), "promoter:10,intergenic:10");
+        assert_eq!(classify(1000, 1000), "3UTR:1");
+        assert_eq!(classify(0, 10), "intergenic:10");
+        assert_eq!(
+            models.classify(&Interval::new(1460, 1470), |x| x == '+'),
+            vec![(RegionClass::Intergenic, 10)]
+        );
+
+        let numbers = |start, end| models.exon_intron_numbers("T1", &Interval::new(start, end));
+        assert_eq!(numbers(1460, 1470), (Some("1".to_string()), None));
+        assert_eq!(
+            numbers(1095, 1150),
+            (Some("3".to_string()), Some("2".to_string()))
+        );
+        assert_eq!(
+            numbers(1050, 1450),
+            (Some("1-3".to_string()), Some("1-2".to_string()))
+        );
+        assert_eq!(numbers(1350, 1350), (None, Some("1".to_string())));
+        assert_eq!(
+            models.exon_intron_numbers("T2", &Interval::new(1460, 1470)),
+            (None, None)
+        );
+    }
+}
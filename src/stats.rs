@@ -0,0 +1,123 @@
+use crate::error::{FileKind, ParseError, ParseErrorKind};
+use crate::gff::{self, Format};
+use crate::{is_bed_header, BedRecord, Interval};
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+
+/// Per-contig summary of a BED or GTF/GFF file.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ContigStats {
+    pub contig: String,
+    /// Number of records on the contig.
+    pub records: usize,
+    /// Sum of record lengths, in bases.
+    pub bases: u64,
+}
+
+/// Counts BED records and covered bases per contig, in the order contigs first appear.
+/// Accepts the same lines as `validate_bed`.
+pub fn bed_stats(reader: impl io::Read) -> Result<Vec<ContigStats>, ParseError> {
+    let mut stats = Stats::default();
+    for (i, line) in io::BufReader::new(reader).lines().enumerate() {
+        let line =
+            line.map_err(|e| ParseError::new(FileKind::Bed, i + 1, None, ParseErrorKind::Io(e)))?;
+        if is_bed_header(&line) {
+            continue;
+        }
+        let rec = BedRecord::from_line(&line, i)?;
+        stats.add(&rec.contig, &rec.interval);
+    }
+    Ok(stats.contigs)
+}
+
+/// Counts GTF/GFF features and covered bases per contig, in the order contigs first appear.
+/// Accepts the same lines as `validate_annotation`.
+pub fn annotation_stats(
+    reader: impl io::Read,
+    format: Format,
+) -> Result<Vec<ContigStats>, ParseError> {
+    let mut stats = Stats::default();
+    for rec in gff::Reader::new(reader, format, None) {
+        let rec = rec?;
+        stats.add(&rec.contig, &rec.interval);
+    }
+    Ok(stats.contigs)
+}
+
+/// Writes contig statistics as a TSV table followed by a total row.
+pub fn write_stats(stats: &[ContigStats], writer: impl io::Write) -> anyhow::Result<()> {
+    let mut writer = io::BufWriter::new(writer);
+    writeln!(writer, "#contig\trecords\tbases")?;
+    for s in stats {
+        writeln!(writer, "{}\t{}\t{}", s.contig, s.records, s.bases)?;
+    }
+    writeln!(
+        writer,
+        "total\t{}\t{}",
+        stats.iter().map(|s| s.records).sum::<usize>(),
+        stats.iter().map(|s| s.bases).sum::<u64>()
+    )?;
+    Ok(())
+}
+
+/// Contig statistics in the order contigs first appear.
+#[derive(Default)]
+struct Stats {
+    contigs: Vec<ContigStats>,
+    index: HashMap<String, usize>,
+}
+
+impl Stats {
+    fn add(&mut self, contig: &str, interval: &Interval) {
+        let idx = *self.index.entry(contig.to_string()).or_insert_with(|| {
+            self.contigs.push(ContigStats {
+                contig: contig.to_string(),
+                ..Default::default()
+            });
+            self.contigs.len() - 1
+        });
+        self.contigs[idx].records += 1;
+        self.contigs[idx].bases += interval.end - interval.start;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bed_stats() {
+        let bed = "chr1\t10\t50\nchr1\t100\t150\nchr2\t5\t55\n";
+        let stats = bed_stats(bed.as_bytes()).unwrap();
+        assert_eq!(
+            stats,
+            vec![
+                ContigStats {
+                    contig: "chr1".to_string(),
+                    records: 2,
+                    bases: 90
+                },
+                ContigStats {
+                    contig: "chr2".to_string(),
+                    records: 1,
+                    bases: 50
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_stats_errors() {
+        let bed = "track name=x\n\nchr1\t10\t50\nchr1\t10\tX\n";
+        let e = bed_stats(bed.as_bytes()).unwrap_err();
+        assert_eq!((e.file, e.line, e.column), (FileKind::Bed, 4, Some(3)));
+        assert!(matches!(e.kind, ParseErrorKind::InvalidNumber(_)));
+
+        let gtf = "chr1\thavana\tgene\t11\t100\t.\t+\t.\tgene_name \"A\";\n";
+        let stats = annotation_stats(gtf.as_bytes(), Format::Gtf).unwrap();
+        assert_eq!((stats[0].records, stats[0].bases), (1, 90));
+        let gtf = "chr1\thavana\tgene\t11\n";
+        let e = annotation_stats(gtf.as_bytes(), Format::Gtf).unwrap_err();
+        assert_eq!((e.file, e.line), (FileKind::Annotation, 1));
+    }
+}
@@ -0,0 +1,616 @@
+//! Tabix (`.tbi`) and CSI (`.csi`) indices of bgzipped annotation files, used to decompress
+//! only the BGZF blocks holding features of the BED regions.
+//!
+//! Both index formats assign each feature to the smallest bin of a hierarchical binning scheme
+//! that contains it, and list for each bin the ranges of virtual offsets ("chunks") where its
+//! features are stored.
+
+use crate::bgzf;
+use crate::contig::ContigAliases;
+use crate::Interval;
+use flate2::read::MultiGzDecoder;
+use std::collections::HashMap;
+use std::io::{self, BufRead, Read};
+use std::path::{Path, PathBuf};
+
+/// A range of virtual offsets in the indexed file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct Chunk {
+    pub(crate) start: u64,
+    pub(crate) end: u64,
+}
+
+/// Bins and chunks of a tabix or CSI index.
+#[derive(Debug)]
+pub struct Index {
+    /// Size of the smallest bins, as a power of two.
+    min_shift: u32,
+    /// Number of levels of bins below the root bin.
+    depth: u32,
+    /// Bins of each contig.
+    contigs: Vec<ContigBins>,
+}
+
+/// Bins of a contig in a tabix or CSI index.
+#[derive(Debug)]
+struct ContigBins {
+    /// Contig name as written in the annotation file.
+    name: String,
+    /// Chunks of each bin.
+    chunks: HashMap<u32, Vec<Chunk>>,
+    /// Smallest virtual offset of the features overlapping each bin, in CSI indices.
+    bin_offsets: HashMap<u32, u64>,
+    /// Smallest virtual offset of the features overlapping each window of `2^min_shift` bases,
+    /// in tabix indices.
+    linear: Vec<u64>,
+}
+
+/// Returns the index file next to a bgzipped file, trying `.tbi` before `.csi`.
+pub fn find_index(path: &Path) -> Option<PathBuf> {
+    ["tbi", "csi"].into_iter().find_map(|ext| {
+        let mut index = path.as_os_str().to_owned();
+        index.push(".");
+        index.push(ext);
+        let index = PathBuf::from(index);
+        index.is_file().then_some(index)
+    })
+}
+
+fn read_i32(reader: &mut impl Read) -> io::Result<i32> {
+    let mut buf = [0; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(i32::from_le_bytes(buf))
+}
+
+fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn read_count(reader: &mut impl Read) -> anyhow::Result<usize> {
+    usize::try_from(read_i32(reader)?).map_err(|_| anyhow::anyhow!("Negative count in index"))
+}
+
+/// Reads the NUL-separated contig names of the tabix header, following its 6 integer fields.
+fn read_names(reader: &mut impl Read) -> anyhow::Result<Vec<String>> {
+    for _ in 0..6 {
+        read_i32(reader)?;
+    }
+    let mut names = vec![0; read_count(reader)?];
+    reader.read_exact(&mut names)?;
+    names
+        .split(|&x| x == 0)
+        .filter(|x| !x.is_empty())
+        .map(|x| Ok(String::from_utf8(x.to_vec())?))
+        .collect()
+}
+
+impl Index {
+    /// Reads a bgzipped tabix or CSI index.
+    pub fn from_reader(reader: impl io::Read) -> anyhow::Result<Self> {
+        let mut reader = io::BufReader::new(MultiGzDecoder::new(reader));
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        let csi = match &magic {
+            b"TBI\x01" => false,
+            b"CSI\x01" => true,
+            _ => return Err(anyhow::anyhow!("Not a tabix or CSI index")),
+        };
+        let (min_shift, depth, names) = if csi {
+            let min_shift = read_i32(&mut reader)? as u32;
+            let depth = read_i32(&mut reader)? as u32;
+            // The largest bins span 2^(min_shift + 3 * depth) bases:
+            if depth > 10 || min_shift >= 64 || min_shift + 3 * depth >= 64 {
+                return Err(anyhow::anyhow!("Invalid binning scheme in CSI index"));
+            }
+            let mut aux = vec![0; read_count(&mut reader)?];
+            reader.read_exact(&mut aux)?;
+            if aux.is_empty() {
+                return Err(anyhow::anyhow!(
+                    "CSI index without contig names, index the file with tabix --csi"
+                ));
+            }
+            (min_shift, depth, read_names(&mut aux.as_slice())?)
+        } else {
+            let n_ref = read_count(&mut reader)?;
+            let names = read_names(&mut reader)?;
+            if names.len() != n_ref {
+                return Err(anyhow::anyhow!("Invalid contig names in tabix index"));
+            }
+            (14, 5, names)
+        };
+        let n_ref = if csi {
+            read_count(&mut reader)?
+        } else {
+            names.len()
+        };
+        if n_ref != names.len() {
+            return Err(anyhow::anyhow!("Invalid contig names in CSI index"));
+        }
+        // Bin holding metadata rather than features:
+        let pseudo_bin = ((1 << (3 * depth + 3)) - 1) / 7 + 1;
+        let mut contigs = vec![];
+        for name in names {
+            let mut bins = HashMap::new();
+            let mut bin_offsets = HashMap::new();
+            for _ in 0..read_count(&mut reader)? {
+                let bin = read_i32(&mut reader)? as u32;
+                if csi {
+                    bin_offsets.insert(bin, read_u64(&mut reader)?);
+                }
+                let mut chunks = vec![];
+                for _ in 0..read_count(&mut reader)? {
+                    let start = read_u64(&mut reader)?;
+                    let end = read_u64(&mut reader)?;
+                    chunks.push(Chunk { start, end });
+                }
+                if bin != pseudo_bin {
+                    bins.insert(bin, chunks);
+                }
+            }
+            let mut linear = vec![];
+            if !csi {
+                for _ in 0..read_count(&mut reader)? {
+                    linear.push(read_u64(&mut reader)?);
+                }
+            }
+            contigs.push(ContigBins {
+                name,
+                chunks: bins,
+                bin_offsets,
+                linear,
+            });
+        }
+        Ok(Self {
+            min_shift,
+            depth,
+            contigs,
+        })
+    }
+
+    /// Bins that may hold features overlapping a 0-based, half-open interval.
+    fn bins(&self, interval: &Interval) -> Vec<u32> {
+        let mut bins = vec![];
+        let mut shift = self.min_shift + 3 * self.depth;
+        let max = (1 << shift) - 1;
+        let start = interval.start.min(max);
+        let end = (interval.end.max(interval.start + 1) - 1).min(max);
+        let mut offset = 0;
+        for level in 0..=self.depth {
+            let first = offset + (start >> shift);
+            let last = offset + (end >> shift);
+            bins.extend((first..=last).map(|x| x as u32));
+            offset += 1 << (3 * level);
+            shift -= 3;
+        }
+        bins
+    }
+
+    /// Smallest virtual offset of the features that may overlap a position, below which
+    /// chunks can be skipped. As in htslib, CSI indices use the offset of the bin of the
+    /// position, or of the closest bin to its left or above it.
+    fn min_offset(&self, contig: &ContigBins, start: u64) -> u64 {
+        let shift = self.min_shift + 3 * self.depth;
+        let window = start.min((1 << shift) - 1) >> self.min_shift;
+        if let Some(last) = contig.linear.len().checked_sub(1) {
+            return contig.linear[(window as usize).min(last)];
+        }
+        let parent = |bin: u64| (bin - 1) >> 3;
+        let mut bin = ((1 << (3 * self.depth)) - 1) / 7 + window;
+        loop {
+            if let Some(&offset) = contig.bin_offsets.get(&(bin as u32)) {
+                return offset;
+            }
+            if bin == 0 {
+                return 0;
+            }
+            bin = match bin > (parent(bin) << 3) + 1 {
+                true => bin - 1,
+                false => parent(bin),
+            };
+        }
+    }
+
+    /// Virtual offset from which all features start after a position, above which chunks can
+    /// be skipped. As in htslib, this is the start of the closest bin to the right of the
+    /// position, or above it if the position is in the last child of its parent.
+    fn max_offset(&self, contig: &ContigBins, end: u64) -> u64 {
+        let shift = self.min_shift + 3 * self.depth;
+        let window = end.saturating_sub(1).min((1 << shift) - 1) >> self.min_shift;
+        let parent = |bin: u64| (bin - 1) >> 3;
+        let mut bin = ((1 << (3 * self.depth)) - 1) / 7 + window + 1;
+        loop {
+            while bin % 8 == 1 {
+                bin = parent(bin);
+            }
+            if bin == 0 {
+                return u64::MAX;
+            }
+            let chunks = contig.chunks.get(&(bin as u32));
+            if let Some(offset) = chunks.and_then(|x| x.iter().map(|x| x.start).min()) {
+                return offset;
+            }
+            bin += 1;
+        }
+    }
+
+    /// Chunks holding the features of the contigs with intervals, or all features of a contig
+    /// if its intervals are `None`, sorted by offset with overlapping chunks merged. Contig
+    /// names are compared after normalization.
+    pub(crate) fn chunks(
+        &self,
+        intervals: &HashMap<String, Option<Vec<Interval>>>,
+        aliases: &ContigAliases,
+    ) -> Vec<Chunk> {
+        let mut chunks: Vec<Chunk> = vec![];
+        for contig in &self.contigs {
+            match intervals.get(aliases.normalize(&contig.name).as_ref()) {
+                None => {}
+                Some(None) => chunks.extend(contig.chunks.values().flatten()),
+                Some(Some(intervals)) => {
+                    for interval in intervals {
+                        // Chunks of large bins may span features far from the interval:
+                        let min_offset = self.min_offset(contig, interval.start);
+                        let max_offset = self.max_offset(contig, interval.end);
+                        let bins = self.bins(interval);
+                        let selected = bins
+                            .iter()
+                            .filter_map(|x| contig.chunks.get(x))
+                            .flatten()
+                            .filter(|x| x.end > min_offset && x.start < max_offset)
+                            .map(|x| Chunk {
+                                start: x.start.max(min_offset),
+                                end: x.end.min(max_offset),
+                            });
+                        chunks.extend(selected);
+                    }
+                }
+            }
+        }
+        chunks.sort_unstable();
+        let mut merged: Vec<Chunk> = vec![];
+        for chunk in chunks {
+            match merged.last_mut() {
+                Some(last) if chunk.start <= last.end => last.end = last.end.max(chunk.end),
+                _ => merged.push(chunk),
+            }
+        }
+        merged
+    }
+}
+
+/// Reads the lines stored in chunks of a BGZF file, in order.
+pub(crate) struct ChunkReader<R: io::Read + io::Seek> {
+    reader: bgzf::Reader<R>,
+    /// Chunks left to read, in reverse order.
+    chunks: Vec<Chunk>,
+    end: u64,
+    line: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: io::Read + io::Seek> ChunkReader<R> {
+    pub(crate) fn new(reader: bgzf::Reader<R>, mut chunks: Vec<Chunk>) -> Self {
+        chunks.reverse();
+        Self {
+            reader,
+            chunks,
+            end: 0,
+            line: vec![],
+            pos: 0,
+        }
+    }
+
+    pub(crate) fn into_inner(self) -> bgzf::Reader<R> {
+        self.reader
+    }
+
+    /// Reads the next line into `line`. Returns false once all chunks are read.
+    fn next_line(&mut self) -> io::Result<bool> {
+        self.line.clear();
+        self.pos = 0;
+        while self.reader.virtual_offset() >= self.end {
+            match self.chunks.pop() {
+                Some(chunk) => {
+                    self.reader.seek(chunk.start)?;
+                    self.end = chunk.end;
+                }
+                None => return Ok(false),
+            }
+        }
+        Ok(self.reader.read_until(b'\n', &mut self.line)? > 0)
+    }
+}
+
+impl<R: io::Read + io::Seek> io::Read for ChunkReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.line.len() && !self.next_line()? {
+            return Ok(0);
+        }
+        let n = (self.line.len() - self.pos).min(buf.len());
+        buf[..n].copy_from_slice(&self.line[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Sorts intervals and merges overlapping ones.
+pub(crate) fn merge(mut intervals: Vec<Interval>) -> Vec<Interval> {
+    intervals.sort_unstable();
+    let mut merged: Vec<Interval> = vec![];
+    for interval in intervals {
+        match merged.last_mut() {
+            Some(last) if interval.start <= last.end => last.end = last.end.max(interval.end),
+            _ => merged.push(interval),
+        }
+    }
+    merged
+}
+
+/// Extends the intervals of each contig with the features overlapping them, so that the genes
+/// and transcripts they overlap are read entirely, e.g. to number exons.
+pub(crate) fn add_feature_extents<R: io::Read + io::Seek>(
+    reader: bgzf::Reader<R>,
+    index: &Index,
+    intervals: &mut HashMap<String, Option<Vec<Interval>>>,
+    aliases: &ContigAliases,
+) -> io::Result<bgzf::Reader<R>> {
+    for x in intervals.values_mut().flatten() {
+        *x = merge(std::mem::take(x));
+    }
+    let mut extents: HashMap<String, Vec<Interval>> = HashMap::new();
+    let mut lines = io::BufReader::new(ChunkReader::new(reader, index.chunks(intervals, aliases)));
+    let mut line = String::new();
+    while lines.read_line(&mut line)? > 0 {
+        let tokens: Vec<&str> = line.splitn(6, '\t').collect();
+        let coords = tokens.get(3).zip(tokens.get(4));
+        let coords =
+            coords.and_then(|(start, end)| start.parse::<u64>().ok().zip(end.parse().ok()));
+        let contig = aliases.normalize(tokens[0]);
+        if let (Some((start, end)), Some(Some(wanted))) = (coords, intervals.get(contig.as_ref())) {
+            let feature = Interval::new(start.saturating_sub(1), end);
+            let i = wanted.partition_point(|x| x.end <= feature.start);
+            if wanted.get(i).is_some_and(|x| x.start < feature.end) {
+                extents
+                    .entry(contig.into_owned())
+                    .or_default()
+                    .push(feature);
+            }
+        }
+        line.clear();
+    }
+    for (contig, features) in extents {
+        if let Some(Some(x)) = intervals.get_mut(&contig) {
+            x.extend(features);
+            *x = merge(std::mem::take(x));
+        }
+    }
+    Ok(lines.into_inner().into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{annotate, annotate_tabix, Options, Verbosity};
+    use flate2::write::{DeflateEncoder, GzEncoder};
+    use flate2::Compression;
+    use std::io::Write;
+
+    /// Compresses lines into BGZF blocks of at most `block_size` bytes, so that lines span
+    /// blocks, and returns the virtual offset of the end of each line.
+    fn bgzip(lines: &[&str], block_size: usize) -> (Vec<u8>, Vec<u64>) {
+        let data: Vec<u8> = lines
+            .iter()
+            .flat_map(|x| format!("{x}\n").into_bytes())
+            .collect();
+        let mut bgzf = vec![];
+        let mut block_offsets = vec![];
+        for block in data.chunks(block_size).chain([&[][..]]) {
+            block_offsets.push(bgzf.len() as u64);
+            let mut encoder = DeflateEncoder::new(vec![], Compression::default());
+            encoder.write_all(block).unwrap();
+            let cdata = encoder.finish().unwrap();
+            let bsize = (cdata.len() + 25) as u16;
+            bgzf.extend([
+                0x1f, 0x8b, 8, 4, 0, 0, 0, 0, 0, 0xff, 6, 0, b'B', b'C', 2, 0,
+            ]);
+            bgzf.extend(bsize.to_le_bytes());
+            bgzf.extend(cdata);
+            let mut crc = flate2::Crc::new();
+            crc.update(block);
+            bgzf.extend(crc.sum().to_le_bytes());
+            bgzf.extend((block.len() as u32).to_le_bytes());
+        }
+        let mut ends = vec![];
+        let mut pos = 0;
+        for line in lines {
+            pos += line.len() + 1;
+            let (block, offset) = (pos / block_size, pos % block_size);
+            ends.push((block_offsets[block] << 16) | offset as u64);
+        }
+        (bgzf, ends)
+    }
+
+    fn reg2bin(start: u64, end: u64) -> u32 {
+        let end = end - 1;
+        for (shift, offset) in [(14, 4681), (17, 585), (20, 73), (23, 9), (26, 1)] {
+            if start >> shift == end >> shift {
+                return (offset + (start >> shift)) as u32;
+            }
+        }
+        0
+    }
+
+    /// Builds a tabix index of a GTF file compressed with `bgzip`.
+    fn tabix(lines: &[&str], ends: &[u64]) -> Vec<u8> {
+        let mut contigs: Vec<(String, HashMap<u32, Vec<Chunk>>)> = vec![];
+        let mut start = 0;
+        for (line, &end) in lines.iter().zip(ends) {
+            let tokens: Vec<&str> = line.split('\t').collect();
+            if contigs.last().is_none_or(|x| x.0 != tokens[0]) {
+                contigs.push((tokens[0].to_string(), HashMap::new()));
+            }
+            let bin = reg2bin(
+                tokens[3].parse::<u64>().unwrap() - 1,
+                tokens[4].parse().unwrap(),
+            );
+            let chunks = contigs.last_mut().unwrap().1.entry(bin).or_default();
+            chunks.push(Chunk { start, end });
+            start = end;
+        }
+        let mut index = b"TBI\x01".to_vec();
+        let names: Vec<u8> = contigs
+            .iter()
+            .flat_map(|x| format!("{}\0", x.0).into_bytes())
+            .collect();
+        for x in [contigs.len(), 2, 1, 4, 5, b'#' as usize, 0, names.len()] {
+            index.extend((x as i32).to_le_bytes());
+        }
+        index.extend(names);
+        for (_, bins) in &contigs {
+            index.extend((bins.len() as i32).to_le_bytes());
+            for (bin, chunks) in bins {
+                index.extend(bin.to_le_bytes());
+                index.extend((chunks.len() as i32).to_le_bytes());
+                for chunk in chunks {
+                    index.extend(chunk.start.to_le_bytes());
+                    index.extend(chunk.end.to_le_bytes());
+                }
+            }
+            index.extend(0i32.to_le_bytes());
+        }
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+        encoder.write_all(&index).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn test_invalid_binning() {
+        let csi = |min_shift: i32, depth: i32| {
+            let mut index = b"CSI\x01".to_vec();
+            for x in [min_shift, depth, 0] {
+                index.extend(x.to_le_bytes());
+            }
+            let mut encoder = GzEncoder::new(vec![], Compression::default());
+            encoder.write_all(&index).unwrap();
+            Index::from_reader(encoder.finish().unwrap().as_slice())
+                .err()
+                .unwrap()
+                .to_string()
+        };
+        assert!(csi(14, 5).contains("without contig names"));
+        for (min_shift, depth) in [(40, 10), (61, 1), (64, 0), (14, 11), (-1, 5), (14, -1)] {
+            assert_eq!(csi(min_shift, depth), "Invalid binning scheme in CSI index");
+        }
+    }
+
+    #[test]
+    fn test_annotate_tabix() {
+        let gtf = [
+            "chr1\tHAVANA\tgene\t11\t1000000\t.\t+\t.\tgene_name \"LONG\";",
+            "chr1\tHAVANA\tgene\t101\t200\t.\t+\t.\tgene_name \"G1\";",
+            "chr1\tHAVANA\texon\t101\t120\t.\t+\t.\tgene_name \"G1\"; transcript_id \"T1\";",
+            "chr1\tHAVANA\texon\t181\t200\t.\t+\t.\tgene_name \"G1\"; transcript_id \"T1\";",
+            "chr1\tHAVANA\tgene\t500001\t500100\t.\t-\t.\tgene_name \"G2\";",
+            "chr2\tHAVANA\tgene\t1\t100\t.\t+\t.\tgene_name \"G3\";",
+            "chr3\tHAVANA\tgene\t1\t100\t.\t+\t.\tgene_name \"G4\";",
+        ];
+        let (bgzf, ends) = bgzip(&gtf, 50);
+        let index = Index::from_reader(tabix(&gtf, &ends).as_slice()).unwrap();
+
+        let bed = "chr1\t185\t190\nchr3\t10\t20\n1\t500000\t500010\nchrX\t1\t2\n";
+        let options = Options {
+            fields: crate::parse_fields("gene_name,exon_numbers").unwrap(),
+            verbosity: Verbosity::Quiet,
+            ..Default::default()
+        };
+        let mut expected = vec![];
+        annotate(
+            bed.as_bytes(),
+            gtf.join("\n").as_bytes(),
+            &mut expected,
+            &options,
+        )
+        .unwrap();
+        let mut output = vec![];
+        let treader = io::Cursor::new(&bgzf);
+        annotate_tabix(bed.as_bytes(), treader, &index, &mut output, &options).unwrap();
+        assert_eq!(output, expected);
+        assert!(String::from_utf8(output)
+            .unwrap()
+            .starts_with("chr1\t185\t190\tG1\t2\n"));
+
+        let intervals = HashMap::from([("3".to_string(), Some(vec![Interval::new(9, 20)]))]);
+        let chunks = index.chunks(&intervals, &ContigAliases::default());
+        let mut lines = String::new();
+        let reader = bgzf::Reader::new(io::Cursor::new(&bgzf));
+        ChunkReader::new(reader, chunks)
+            .read_to_string(&mut lines)
+            .unwrap();
+        assert_eq!(lines, format!("{}\n", gtf[6]));
+    }
+
+    /// Genes of GENCODE v43 on chr21 starting before 8 Mb and on chrM, sorted with
+    /// `sort -s -k1,1 -k4,4n`, compressed with `bgzip` and indexed with `tabix -p gff`.
+    const FIXTURE: &[u8] = include_bytes!("../data/test/gencode.v43.subset.gtf.gz");
+    const FIXTURE_INDEX: &[u8] = include_bytes!("../data/test/gencode.v43.subset.gtf.gz.tbi");
+
+    #[test]
+    fn test_tabix_fixture() {
+        let index = Index::from_reader(FIXTURE_INDEX).unwrap();
+        let mut gtf = String::new();
+        MultiGzDecoder::new(FIXTURE)
+            .read_to_string(&mut gtf)
+            .unwrap();
+        let lines: Vec<&str> = gtf.lines().filter(|x| !x.starts_with('#')).collect();
+
+        // The chunks of a region hold every line overlapping it, and few others. chrM fits in
+        // the smallest bin, so all of it is read:
+        for (contig, start, end, count) in [
+            ("chr21", 5_010_000, 5_020_000, 22),
+            ("chr21", 7_050_000, 7_060_000, 4),
+            ("chr21", 7_900_000, 8_000_000, 0),
+            ("chrM", 3000, 3500, 143),
+        ] {
+            let name = ContigAliases::default().normalize(contig).to_string();
+            let intervals = HashMap::from([(name, Some(vec![Interval::new(start, end)]))]);
+            let chunks = index.chunks(&intervals, &ContigAliases::default());
+            let reader = bgzf::Reader::new(io::Cursor::new(FIXTURE));
+            let mut selected = String::new();
+            ChunkReader::new(reader, chunks)
+                .read_to_string(&mut selected)
+                .unwrap();
+            let selected: Vec<&str> = selected.lines().collect();
+            let overlapping: Vec<&str> = lines
+                .iter()
+                .copied()
+                .filter(|x| {
+                    let tokens: Vec<&str> = x.split('\t').collect();
+                    tokens[0] == contig
+                        && tokens[3].parse::<u64>().unwrap() <= end
+                        && tokens[4].parse::<u64>().unwrap() > start
+                })
+                .collect();
+            assert!(overlapping.iter().all(|x| selected.contains(x)));
+            assert!(selected
+                .iter()
+                .all(|x| x.starts_with(&format!("{contig}\t"))));
+            assert_eq!(selected.len(), count, "{contig}:{start}-{end}");
+        }
+
+        let bed =
+            "chr21\t5010000\t5020000\nchr21\t7050000\t7060000\nchrM\t3000\t3500\nchr1\t1\t2\n";
+        let options = Options {
+            fields: crate::parse_fields("gene_name,transcript_id,exon_numbers").unwrap(),
+            verbosity: Verbosity::Quiet,
+            ..Default::default()
+        };
+        let mut expected = vec![];
+        annotate(bed.as_bytes(), gtf.as_bytes(), &mut expected, &options).unwrap();
+        let mut output = vec![];
+        let treader = io::Cursor::new(FIXTURE);
+        annotate_tabix(bed.as_bytes(), treader, &index, &mut output, &options).unwrap();
+        assert_eq!(output, expected);
+    }
+}
@@ -0,0 +1,65 @@
+use crate::error::{FileKind, ParseError, ParseErrorKind};
+use crate::gff::{self, Format};
+use crate::{is_bed_header, BedRecord};
+use std::io::{self, BufRead};
+
+/// Checks that every record of a BED file has a contig and valid coordinates.
+/// Returns the number of records.
+pub fn validate_bed(reader: impl io::Read) -> Result<usize, ParseError> {
+    let mut count = 0;
+    for (i, line) in io::BufReader::new(reader).lines().enumerate() {
+        let line =
+            line.map_err(|e| ParseError::new(FileKind::Bed, i + 1, None, ParseErrorKind::Io(e)))?;
+        if is_bed_header(&line) {
+            continue;
+        }
+        BedRecord::from_line(&line, i)?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Checks that every record of a GTF/GFF file has 9 columns, valid coordinates and attributes.
+/// Returns the number of records.
+pub fn validate_annotation(reader: impl io::Read, format: Format) -> Result<usize, ParseError> {
+    let mut count = 0;
+    for rec in gff::Reader::new(reader, format, None) {
+        rec?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_bed() {
+        assert_eq!(
+            validate_bed("chr1\t10\t50\nchr2\t5\t55\n".as_bytes()).unwrap(),
+            2
+        );
+        assert!(validate_bed("chr1\t10\n".as_bytes()).is_err());
+        assert!(validate_bed("chr1\t50\t10\n".as_bytes()).is_err());
+        assert_eq!(
+            validate_bed("chr1\t1\t2\nchr2\t1\t2\nchr1\t3\t4\n".as_bytes()).unwrap(),
+            3
+        );
+    }
+
+    #[test]
+    fn test_validate_annotation() {
+        let gtf = "chr1\thavana\tgene\t1\t100\t.\t+\t.\tgene_name \"A\";\n";
+        assert_eq!(validate_annotation(gtf.as_bytes(), Format::Gtf).unwrap(), 1);
+
+        let gtf = "chr1\thavana\tgene\t1\tX\t.\t+\t.\tgene_name \"A\";\n";
+        let e = validate_annotation(gtf.as_bytes(), Format::Gtf).unwrap_err();
+        assert_eq!((e.line, e.column), (1, Some(5)));
+        assert!(matches!(e.kind, ParseErrorKind::InvalidNumber(_)));
+
+        let gtf = "chr1\thavana\tgene\t1\t100\t.\t+\t.\tgene_name;\n";
+        let e = validate_annotation(gtf.as_bytes(), Format::Gtf).unwrap_err();
+        assert!(matches!(e.kind, ParseErrorKind::InvalidAttribute(_)));
+    }
+}